@@ -7,6 +7,9 @@ use crate::template::Template;
 
 pub const URL_GET_COMPANY: &str = "https://corplink.volcengine.cn/api/match";
 
+const DEFAULT_REPORT_OS: &str = "Android";
+const DEFAULT_REPORT_OS_VERSION: &str = "2";
+
 const URL_GET_LOGIN_METHOD: &str = "{{url}}/api/login/setting?os={{os}}&os_version={{version}}";
 const URL_GET_TPS_LOGIN_METHOD: &str = "{{url}}/api/tpslogin/link?os={{os}}&os_version={{version}}";
 const URL_GET_TPS_TOEKN_CHECK: &str =
@@ -63,8 +66,15 @@ pub struct ApiUrl {
 
 impl ApiUrl {
     pub fn new(conf: &Config) -> ApiUrl {
-        let os = "Android".to_string();
-        let version = "2".to_string();
+        // some tenants apply device policy by os
+        let os = conf
+            .report_os
+            .clone()
+            .unwrap_or(DEFAULT_REPORT_OS.to_string());
+        let version = conf
+            .report_os_version
+            .clone()
+            .unwrap_or(DEFAULT_REPORT_OS_VERSION.to_string());
         let mut api_template = HashMap::new();
 
         api_template.insert(ApiName::LoginMethod, Template::new(URL_GET_LOGIN_METHOD));
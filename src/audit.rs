@@ -0,0 +1,64 @@
+use std::fs;
+use std::io::Write;
+
+use chrono::Utc;
+use serde::Serialize;
+
+pub const EVENT_LOGIN: &str = "login";
+pub const EVENT_CONNECT: &str = "connect";
+pub const EVENT_HANDSHAKE: &str = "handshake";
+pub const EVENT_RECONNECT: &str = "reconnect";
+pub const EVENT_DISCONNECT: &str = "disconnect";
+pub const EVENT_ERROR: &str = "error";
+
+#[derive(Serialize)]
+struct AuditEvent<'a> {
+    time: String,
+    event: &'a str,
+    user: &'a str,
+    server: &'a str,
+    detail: &'a str,
+}
+
+// append-only audit trail of connection events, one json per line
+#[derive(Clone)]
+pub struct AuditLog {
+    file: Option<String>,
+    user: String,
+}
+
+impl AuditLog {
+    pub fn new(file: Option<String>, user: &str) -> AuditLog {
+        AuditLog {
+            file,
+            user: user.to_string(),
+        }
+    }
+
+    pub fn record(&self, event: &str, server: &str, detail: &str) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let e = AuditEvent {
+            time: Utc::now().to_rfc3339(),
+            event,
+            user: &self.user,
+            server,
+            detail,
+        };
+        let line = serde_json::to_string(&e).unwrap();
+        // sync every event so that it will not be lost if we crash
+        let result = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .and_then(|mut f| {
+                writeln!(f, "{}", line)?;
+                f.sync_data()
+            });
+        if let Err(err) = result {
+            log::warn!("failed to write audit log to {}: {}", file, err);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use chrono::Utc;
 use std::collections::HashMap;
 use std::fmt;
+use std::io::IsTerminal;
 use std::path;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -9,30 +10,62 @@ use std::{fs, io};
 
 use cookie::Cookie as RawCookie;
 use cookie_store::{Cookie, CookieStore};
+use regex::Regex;
 use reqwest::header;
 use reqwest::{ClientBuilder, Response, Url};
 use reqwest_cookie_store::CookieStoreMutex;
 use serde::de::DeserializeOwned;
 use serde_json::{json, Map, Value};
 use sha2::Digest;
+use tokio::net;
 
 use crate::api::{ApiName, ApiUrl, URL_GET_COMPANY};
 use crate::config::{
-    Config, WgConf, PLATFORM_CORPLINK, PLATFORM_LARK, PLATFORM_LDAP, PLATFORM_OIDC,
-    STRATEGY_DEFAULT, STRATEGY_LATENCY,
+    Config, WgConf, DEFAULT_SERVER_CACHE_TTL_SECS, DEFAULT_WG_KEEPALIVE_INTERVAL,
+    PLATFORM_CORPLINK, PLATFORM_LARK, PLATFORM_LDAP, PLATFORM_OIDC, PLATFORM_WEIXIN,
+    ROUTE_FAMILY_BOTH, ROUTE_FAMILY_V4, ROUTE_FAMILY_V6, STRATEGY_DEFAULT, STRATEGY_FIRST,
+    STRATEGY_LATENCY,
 };
 use crate::qrcode::TerminalQrCode;
 use crate::resp::*;
 use crate::state::State;
+use crate::tls;
 use crate::totp::{totp_offset, TIME_STEP};
 use crate::utils;
 
 const COOKIE_FILE_SUFFIX: &str = "cookies.json";
+const CSRF_TOKEN: &str = "csrf-token";
+const SERVER_CACHE_FILE_SUFFIX: &str = "servers.json";
+const PID_FILE_SUFFIX: &str = "corplink.pid";
+const STATUS_FILE_SUFFIX: &str = "status.json";
+const DEFAULT_REQUEST_ID_HEADER: &str = "X-Request-Id";
+const DEFAULT_PING_TIMEOUT_MS: u64 = 2000;
 const USER_AGENT: &str = "CorpLink/201000 (GooglePixel; Android 10; en)";
+const ROUTE_MODE_SPLIT: &str = "Split";
+const OTP_RETRY: u32 = 2;
+// codes can be fed by env instead of stdin, e.g. fetched from a shared mailbox
+const EMAIL_CODE_ENV: &str = "CORPLINK_EMAIL_CODE";
+const OTP_ENV: &str = "CORPLINK_OTP";
+const PREWARM_TIMEOUT: Duration = Duration::from_secs(3);
+// code sent to email is reused if the login is interrupted within this window
+const EMAIL_CODE_WINDOW: Duration = Duration::from_secs(300);
+// stop polling third party auth if it's not finished in time
+const TPS_POLL_TIMEOUT: Duration = Duration::from_secs(300);
+const REPORT_TYPE_KEEPALIVE: &str = "100";
+const REPORT_TYPE_DISCONNECT: &str = "101";
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     ReqwestError(reqwest::Error),
+    // 2fa code is rejected but session is still valid
+    InvalidOtp(String),
+    // device is revoked or 2fa seed is invalid, need to login again
+    Unregistered(String),
+    // gateway is temporarily unavailable, should try another one
+    Unavailable(String),
+    // 5xx from server, may be overloaded
+    ServerError(String),
     Error(String),
 }
 
@@ -40,20 +73,54 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::ReqwestError(err) => err.fmt(f),
-            Error::Error(err) => {
+            Error::InvalidOtp(err)
+            | Error::Unregistered(err)
+            | Error::Unavailable(err)
+            | Error::ServerError(err)
+            | Error::Error(err) => {
                 write!(f, "{}", err)
             }
         }
     }
 }
 
+impl std::error::Error for Error {}
+
+// server list from the last successful list_vpn
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ServerCache {
+    updated_at: u64,
+    servers: Vec<RespVpnInfo>,
+}
+
+impl Error {
+    // network problems and server errors may be gone after a while, but bad credentials won't
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::ReqwestError(err) => {
+                err.is_timeout()
+                    || err.is_connect()
+                    || err.status().is_some_and(|status| status.is_server_error())
+            }
+            Error::ServerError(_) => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Client {
     conf: Config,
     cookie: Arc<CookieStoreMutex>,
     c: reqwest::Client,
+    // client for apis of vpn gateways, which are requested by ip
+    vpn_c: reqwest::Client,
     api_url: ApiUrl,
     date_offset_sec: i32,
+    // servers which failed to handshake in this run
+    excluded_servers: Vec<String>,
+    // servers listed by check_session, reused once so they are not listed twice
+    listed_servers: Option<Vec<RespVpnInfo>>,
 }
 
 unsafe impl Send for Client {}
@@ -91,24 +158,312 @@ pub async fn get_company_url(code: &str) -> Result<RespCompany, Error> {
     }
 }
 
+// same request as get_company_url, but tells which step fails
+// returns company info and sha256 fingerprint of the server cert
+pub async fn check_company(code: &str) -> Result<(RespCompany, Option<String>), Error> {
+    let url = Url::parse(URL_GET_COMPANY).unwrap();
+    let host = url.host_str().unwrap();
+    let addrs: Vec<String> = match net::lookup_host((host, 443)).await {
+        Ok(addrs) => addrs.map(|addr| addr.ip().to_string()).collect(),
+        Err(err) => return Err(Error::Error(format!("dns failure of {}: {}", host, err))),
+    };
+    log::info!("{} is resolved to {}", host, addrs.join(","));
+
+    let c = ClientBuilder::new()
+        // alow invalid certs because this cert is signed by corplink
+        .danger_accept_invalid_certs(true)
+        .tls_info(true)
+        .build()
+        .map_err(Error::ReqwestError)?;
+    let mut m = Map::new();
+    m.insert("code".to_string(), json!(code));
+    let body = serde_json::to_string(&m).unwrap();
+
+    let resp = match c.post(URL_GET_COMPANY).body(body).send().await {
+        Ok(resp) => resp,
+        Err(err) => {
+            let mut detail = err.to_string();
+            let mut source = std::error::Error::source(&err);
+            while let Some(err) = source {
+                detail = format!("{}: {}", detail, err);
+                source = err.source();
+            }
+            let lower = detail.to_lowercase();
+            let kind = if ["tls", "ssl", "certificate", "handshake"]
+                .iter()
+                .any(|keyword| lower.contains(keyword))
+            {
+                "tls failure"
+            } else {
+                "connection failure"
+            };
+            return Err(Error::Error(format!("{} of {}: {}", kind, host, detail)));
+        }
+    };
+    let cert = resp
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+        .map(|der| format!("{:x}", sha2::Sha256::digest(der)));
+    let status = resp.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Err(Error::Error(format!(
+            "rate limited by {}, please try again later",
+            host
+        )));
+    }
+    if !status.is_success() {
+        return Err(Error::Error(format!("{} returns {}", host, status)));
+    }
+    let resp = match resp.json::<Resp<RespCompany>>().await {
+        Ok(resp) => resp,
+        Err(err) => {
+            return Err(Error::Error(format!(
+                "invalid response from {}: {}",
+                host, err
+            )))
+        }
+    };
+    match resp.code {
+        0 => Ok((resp.data.unwrap(), cert)),
+        _ => Err(Error::Error(format!(
+            "company {} is not found: {}",
+            code,
+            resp.message.unwrap_or_default()
+        ))),
+    }
+}
+
+// only keep valid domains in allowlist to avoid leaking queries of other domains to vpn dns
+fn filter_dns_domains(domains: Vec<String>, allowlist: Option<&[String]>) -> Vec<String> {
+    domains
+        .into_iter()
+        .filter(|domain| {
+            let domain = domain.trim_start_matches('~');
+            if !utils::is_valid_domain(domain) {
+                log::warn!("skip invalid dns domain {}", domain);
+                return false;
+            }
+            if allowlist.is_some_and(|allowlist| {
+                !allowlist
+                    .iter()
+                    .any(|suffix| utils::domain_matches(domain, suffix))
+            }) {
+                log::info!("skip dns domain {} which is not in allowlist", domain);
+                return false;
+            }
+            true
+        })
+        .map(|domain| domain.trim_start_matches('~').to_string())
+        .collect()
+}
+
+// resolve and pre-connect to vpn gateway, so dns and socket path are warm before wg starts
+async fn prewarm_gateway(addr: &str, protocol_mode: i32) -> Result<(), Error> {
+    let addr = match net::lookup_host(addr).await {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => addr,
+            None => return Err(Error::Error(format!("no address found for {}", addr))),
+        },
+        Err(err) => return Err(Error::Error(format!("failed to resolve {}: {}", addr, err))),
+    };
+    match protocol_mode {
+        // tcp
+        1 => {
+            let conn = tokio::time::timeout(PREWARM_TIMEOUT, net::TcpStream::connect(addr)).await;
+            match conn {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(err)) => Err(Error::Error(format!(
+                    "failed to connect to {}: {}",
+                    addr, err
+                ))),
+                Err(_) => Err(Error::Error(format!("connect to {} timeout", addr))),
+            }
+        }
+        // udp, connect will not send anything but fails fast if there is no route
+        _ => {
+            let bind_addr = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let sock = match net::UdpSocket::bind(bind_addr).await {
+                Ok(sock) => sock,
+                Err(err) => {
+                    return Err(Error::Error(format!("failed to bind udp socket: {}", err)))
+                }
+            };
+            match sock.connect(addr).await {
+                Ok(_) => Ok(()),
+                Err(err) => Err(Error::Error(format!(
+                    "failed to connect to {}: {}",
+                    addr, err
+                ))),
+            }
+        }
+    }
+}
+
+// probe the gateway when no handshake is done, and tell user what may be wrong
+// protocol is the one in wg conf, 1 for tcp and 0 for udp
+pub async fn diagnose_gateway(addr: &str, protocol: i32) {
+    let addr = match net::lookup_host(addr).await {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => addr,
+            None => {
+                log::warn!("no address found for gateway {}", addr);
+                return;
+            }
+        },
+        Err(err) => {
+            log::warn!("failed to resolve gateway {}: {}", addr, err);
+            return;
+        }
+    };
+    if protocol == 1 {
+        match tokio::time::timeout(PREWARM_TIMEOUT, net::TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => log::warn!(
+                "tcp port of gateway {} is reachable, handshake may be rejected by server",
+                addr
+            ),
+            Ok(Err(err)) => log::warn!(
+                "tcp port of gateway {} is not reachable: {}, please check your firewall",
+                addr,
+                err
+            ),
+            Err(_) => log::warn!(
+                "connecting to tcp port of gateway {} timeout, it may be blocked by firewall",
+                addr
+            ),
+        }
+        return;
+    }
+    let bind_addr = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let sock = match net::UdpSocket::bind(bind_addr).await {
+        Ok(sock) => sock,
+        Err(err) => {
+            log::warn!("failed to bind udp socket: {}", err);
+            return;
+        }
+    };
+    // wg drops invalid packets silently, but a closed port is reported by icmp
+    let result = match sock.connect(addr).await {
+        Ok(_) => match sock.send(&[0]).await {
+            Ok(_) => {
+                let mut buf = [0u8; 64];
+                tokio::time::timeout(PREWARM_TIMEOUT, sock.recv(&mut buf)).await
+            }
+            Err(err) => Ok(Err(err)),
+        },
+        Err(err) => Ok(Err(err)),
+    };
+    match result {
+        Ok(Err(err)) if err.kind() == io::ErrorKind::ConnectionRefused => log::warn!(
+            "udp port of gateway {} is closed, please try a tcp gateway with vpn_server_name",
+            addr
+        ),
+        Ok(Err(err)) => log::warn!("udp port of gateway {} is not reachable: {}", addr, err),
+        Ok(Ok(_)) => log::warn!(
+            "udp port of gateway {} is reachable, handshake may be rejected by server",
+            addr
+        ),
+        Err(_) => log::warn!(
+            "udp port of gateway {} may be blocked by nat or firewall, please check your firewall or try a tcp gateway with vpn_server_name",
+            addr
+        ),
+    }
+}
+
+// body of keep alive and disconnect reports
+// address is the bare ip assigned by server, its mask is kept in the mask field, so it's reported as is
+fn vpn_report(conf: &WgConf, report_type: &str) -> Map<String, Value> {
+    let mut m = Map::new();
+    m.insert("ip".to_string(), json!(conf.address));
+    m.insert("public_key".to_string(), json!(conf.public_key));
+    m.insert("mode".to_string(), json!(conf.route_mode));
+    m.insert("type".to_string(), json!(report_type));
+    m
+}
+
+// cookie file is placed beside the config file
+pub fn cookie_file(conf: &Config) -> path::PathBuf {
+    side_file(conf, COOKIE_FILE_SUFFIX)
+}
+
+pub fn server_cache_file(conf: &Config) -> path::PathBuf {
+    side_file(conf, SERVER_CACHE_FILE_SUFFIX)
+}
+
+// pid file of daemon mode, beside the config file by default
+pub fn pid_file(conf: &Config) -> path::PathBuf {
+    match &conf.pid_file {
+        Some(f) => path::PathBuf::from(f),
+        None => side_file(conf, PID_FILE_SUFFIX),
+    }
+}
+
+// status of the live connection, beside the config file by default
+pub fn status_file(conf: &Config) -> path::PathBuf {
+    match &conf.status_file {
+        Some(f) => path::PathBuf::from(f),
+        None => side_file(conf, STATUS_FILE_SUFFIX),
+    }
+}
+
+// lock of the interface, beside the config file to guard config and cookies too
+pub fn lock_file(conf: &Config) -> path::PathBuf {
+    conf_dir(conf).join(format!("{}.lock", conf.interface_name.clone().unwrap()))
+}
+
+fn conf_dir(conf: &Config) -> path::PathBuf {
+    let f = conf.conf_file.clone().unwrap();
+    match path::Path::new(&f).parent() {
+        Some(dir) => dir.to_path_buf(),
+        None => path::PathBuf::from("."),
+    }
+}
+
+// file beside the config file, named after the interface
+fn side_file(conf: &Config, suffix: &str) -> path::PathBuf {
+    conf_dir(conf).join(format!(
+        "{}_{}",
+        conf.interface_name.clone().unwrap(),
+        suffix
+    ))
+}
+
 impl Client {
     pub fn new(conf: Config) -> Result<Client, Error> {
-        let f = conf.conf_file.clone().unwrap();
-        let dir = match path::Path::new(&f).parent() {
-            Some(dir) => dir,
-            None => path::Path::new("."),
-        };
-        let cookie_file = dir.join(format!(
-            "{}_{}",
-            conf.interface_name.clone().unwrap(),
-            COOKIE_FILE_SUFFIX
-        ));
+        let cookie_file = cookie_file(&conf);
         log::info!("cookie file is: {}", cookie_file.to_str().unwrap());
+        // cookies contain session tokens
+        utils::check_private_file(&cookie_file);
 
         let mut cookie_store = {
-            let file = fs::File::open(cookie_file).map(io::BufReader::new);
+            let file = fs::File::open(&cookie_file).map(io::BufReader::new);
             match file {
-                Ok(file) => CookieStore::load_json_all(file).unwrap(),
+                Ok(file) => match CookieStore::load_json_all(file) {
+                    Ok(cookie_store) => cookie_store,
+                    Err(err) => {
+                        // file may be truncated by an unclean exit, keep it for debugging and login again
+                        let mut broken = cookie_file.clone().into_os_string();
+                        broken.push(".broken");
+                        log::warn!(
+                            "cookie file {} is corrupted: {}, move it to {} and start with empty cookies",
+                            cookie_file.display(),
+                            err,
+                            broken.to_string_lossy()
+                        );
+                        if let Err(err) = fs::rename(&cookie_file, &broken) {
+                            log::warn!("failed to move {}: {}", cookie_file.display(), err);
+                        }
+                        CookieStore::default()
+                    }
+                },
                 Err(_) => CookieStore::default(),
             }
         };
@@ -131,39 +486,135 @@ impl Client {
                     .insert_raw(&RawCookie::new("device_name", device_name), &server_url);
             }
 
-            if let Some(csrf_token) =
-                cookie_store.get(server_url.domain().unwrap(), "/", "csrf-token")
-            {
-                headers.insert(
-                    "csrf-token",
-                    header::HeaderValue::from_str(csrf_token.value()).unwrap(),
-                );
+            // server may set it with any path, so don't look it up with path /
+            let csrf_token = cookie_store
+                .iter_unexpired()
+                .find(|c| c.name() == CSRF_TOKEN && c.domain.matches(&server_url));
+            match csrf_token {
+                Some(csrf_token) => match header::HeaderValue::from_str(csrf_token.value()) {
+                    Ok(value) => {
+                        log::debug!(
+                            "use csrf-token from cookie of {}{}",
+                            csrf_token.domain().unwrap_or_default(),
+                            csrf_token.path().unwrap_or_default()
+                        );
+                        headers.insert(CSRF_TOKEN, value);
+                    }
+                    Err(e) => log::warn!("invalid csrf-token in cookie: {}", e),
+                },
+                None => {
+                    if conf.state == Some(State::Login) {
+                        log::warn!("no csrf-token found in cookie of {}, requests may be rejected, run reset-all and login again if it keeps failing", server);
+                    }
+                }
+            }
+        }
+
+        // random id of this run to find requests in server logs
+        let request_id_header = conf
+            .request_id_header
+            .clone()
+            .unwrap_or(DEFAULT_REQUEST_ID_HEADER.to_string());
+        if !request_id_header.is_empty() {
+            let request_id = format!("{:016x}", rand::random::<u64>());
+            let header_name = header::HeaderName::from_bytes(request_id_header.as_bytes())
+                .map_err(|e| {
+                    Error::Error(format!("invalid header name {}: {}", request_id_header, e))
+                })?;
+            headers.insert(
+                header_name,
+                header::HeaderValue::from_str(&request_id).unwrap(),
+            );
+            log::info!("request id of this session is {}", request_id);
+        }
+
+        if let Some(custom_headers) = &conf.custom_headers {
+            for (name, value) in custom_headers {
+                let header_name = header::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| Error::Error(format!("invalid header name {}: {}", name, e)))?;
+                let header_value = header::HeaderValue::from_str(value).map_err(|e| {
+                    Error::Error(format!("invalid value of header {}: {}", name, e))
+                })?;
+                headers.insert(header_name, header_value);
             }
+            log::info!(
+                "custom headers: {}",
+                custom_headers
+                    .keys()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(",")
+            );
         }
 
         let cookie_store = Arc::new(CookieStoreMutex::new(cookie_store));
 
-        let c = ClientBuilder::new()
-            // alow invalid certs because this cert is signed by corplink
-            .danger_accept_invalid_certs(true)
-            // for debug
-            // .proxy(reqwest::Proxy::all("socks5://192.168.111.233:8001").unwrap())
-            .user_agent(USER_AGENT)
-            .cookie_provider(Arc::clone(&cookie_store))
-            .default_headers(headers)
-            .timeout(Duration::from_millis(10000))
-            .build();
-        if let Err(err) = c {
-            return Err(Error::ReqwestError(err));
+        let pinned_key = match &conf.server_public_key {
+            Some(key) => Some(
+                utils::pem_to_der(key)
+                    .map_err(|e| Error::Error(format!("invalid server_public_key: {}", e)))?,
+            ),
+            None => None,
+        };
+        let root_cert = match &conf.self_signed_cert {
+            Some(cert) => Some(
+                reqwest::Certificate::from_pem(cert.as_bytes())
+                    .map_err(|e| Error::Error(format!("invalid self_signed_cert: {}", e)))?,
+            ),
+            None => None,
+        };
+        // cert signed by corplink is not trusted by system, so it's checked with pinned key
+        // during handshake instead, other cert settings are ignored
+        let pinned_tls = match pinned_key {
+            Some(key) => Some(
+                tls::pinned_config(&key)
+                    .map_err(|e| Error::Error(format!("invalid server_public_key: {}", e)))?,
+            ),
+            None => None,
+        };
+        let accept_invalid_certs = pinned_tls.is_none()
+            && root_cert.is_none()
+            && conf.accept_invalid_certs.unwrap_or_default();
+        if accept_invalid_certs {
+            log::warn!("server cert is not verified, set accept_invalid_certs to false if the server has a trusted cert");
         }
+        let build = |gateway: bool| {
+            let mut builder = ClientBuilder::new();
+            match &pinned_tls {
+                Some(tls) => builder = builder.use_preconfigured_tls(tls.clone()),
+                None => {
+                    builder = builder.danger_accept_invalid_certs(accept_invalid_certs);
+                    if let Some(cert) = &root_cert {
+                        builder = builder.add_root_certificate(cert.clone());
+                        // vpn apis are requested by gateway ip, so only the issuer is checked
+                        if gateway {
+                            builder = builder.danger_accept_invalid_hostnames(true);
+                        }
+                    }
+                }
+            }
+            builder
+                // for debug
+                // .proxy(reqwest::Proxy::all("socks5://192.168.111.233:8001").unwrap())
+                .user_agent(USER_AGENT)
+                .cookie_provider(Arc::clone(&cookie_store))
+                .default_headers(headers.clone())
+                .timeout(Duration::from_millis(10000))
+                .build()
+                .map_err(Error::ReqwestError)
+        };
+        let c = build(false)?;
+        let vpn_c = build(true)?;
         let conf_bak = conf.clone();
-        let c = c.unwrap();
         Ok(Client {
             conf,
             cookie: Arc::clone(&cookie_store),
             c,
+            vpn_c,
             api_url: ApiUrl::new(&conf_bak),
             date_offset_sec: 0,
+            excluded_servers: Vec::new(),
+            listed_servers: None,
         })
     }
 
@@ -173,19 +624,10 @@ impl Client {
     }
 
     fn save_cookie(&self) {
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(format!(
-                "{}_{}",
-                self.conf.interface_name.clone().unwrap(),
-                COOKIE_FILE_SUFFIX
-            ))
-            .map(io::BufWriter::new)
-            .unwrap();
+        let mut data = Vec::new();
         let c = self.cookie.lock().unwrap();
-        c.save_json(&mut file).unwrap();
+        c.save_json(&mut data).unwrap();
+        utils::write_private_atomic(&cookie_file(&self.conf), &data).unwrap();
     }
 
     async fn request<T: DeserializeOwned+fmt::Debug>(
@@ -195,12 +637,27 @@ impl Client {
     ) -> Result<Resp<T>, Error> {
         let url = self.api_url.get_api_url(&api);
 
+        let c = match api {
+            ApiName::PingVPN
+            | ApiName::ConnectVPN
+            | ApiName::KeepAliveVPN
+            | ApiName::DisconnectVPN => &self.vpn_c,
+            _ => &self.c,
+        };
         let rb = match body {
             Some(body) => {
                 let body = serde_json::to_string(&body).unwrap();
-                self.c.post(url).body(body)
+                c.post(url).body(body)
             }
-            None => self.c.get(url),
+            None => c.get(url),
+        };
+        // dead servers should fail fast when selecting by latency
+        let rb = if matches!(api, ApiName::PingVPN) {
+            rb.timeout(Duration::from_millis(
+                self.conf.ping_timeout_ms.unwrap_or(DEFAULT_PING_TIMEOUT_MS),
+            ))
+        } else {
+            rb
         };
 
         let resp = match rb.send().await {
@@ -208,7 +665,20 @@ impl Client {
             Err(err) => return Err(Error::ReqwestError(err)),
         };
         // TODO: handle special cases
+        if resp.status().is_server_error() {
+            return Err(Error::ServerError(format!(
+                "server error of {:?}: {}",
+                api,
+                resp.status()
+            )));
+        }
+        // servers are pinged concurrently with clones of the client,
+        // so pinging never changes state or saves anything
+        let ping = matches!(api, ApiName::PingVPN);
         if !resp.status().is_success() {
+            if ping {
+                return Err(Error::Error(format!("bad resp code: {}", resp.status())));
+            }
             let msg = format!("logout becuase of bad resp code: {}", resp.status());
             return Err(self.handle_logout_err(msg).await);
         }
@@ -216,7 +686,7 @@ impl Client {
         self.parse_time_offset_from_date_header(&resp);
 
         for (name, _) in resp.headers() {
-            if name.to_string().to_lowercase() == "set-cookie" {
+            if !ping && name.to_string().to_lowercase() == "set-cookie" {
                 log::info!("found set-cookie in header, saving cookie");
                 self.save_cookie();
                 break;
@@ -227,7 +697,9 @@ impl Client {
             return Err(Error::ReqwestError(err));
         }
         let resp = resp.unwrap();
-        log::debug!("api {:#?} resp: {:#?}", api, resp);
+        // resp may carry 2fa secrets, so it's only dumped at trace level
+        log::debug!("api {:#?} resp code: {}", api, resp.code);
+        log::trace!("api {:#?} resp: {:#?}", api, resp);
         Ok(resp)
     }
 
@@ -258,6 +730,20 @@ impl Client {
         return self.conf.state.is_none() || self.conf.state.as_ref().unwrap() == &State::Init;
     }
 
+    // probe an authenticated api to check if the session in cookie is still valid
+    // state will be reset to init if server tells us we're logged out
+    pub async fn check_session(&mut self) -> Result<(), Error> {
+        // listing is what cached servers are used to skip, connecting will find a dead session too
+        if self.conf.use_cached_servers {
+            log::info!("skip checking session because cached servers are used");
+            return Ok(());
+        }
+        let vpn_info = self.list_vpn().await?;
+        self.save_server_cache(&vpn_info);
+        self.listed_servers = Some(vpn_info);
+        Ok(())
+    }
+
     async fn check_tps_token(&mut self, token: &String) -> Result<String, Error> {
         // tps confirmed, try to login with token
         let mut m = Map::new();
@@ -275,22 +761,61 @@ impl Client {
         }
     }
 
+    // check token until auth is finished, so no one needs to press enter
+    async fn poll_tps_token(
+        &mut self,
+        token: &String,
+        interval: Duration,
+    ) -> Result<String, Error> {
+        log::info!("waiting for auth, check it every {}s", interval.as_secs());
+        let start = SystemTime::now();
+        loop {
+            tokio::time::sleep(interval).await;
+            match self.check_tps_token(token).await {
+                Ok(url) => return Ok(url),
+                Err(Error::Error(msg)) => {
+                    if start.elapsed().unwrap_or_default() > TPS_POLL_TIMEOUT {
+                        return Err(Error::Error(format!(
+                            "auth is not finished in {}s: {}",
+                            TPS_POLL_TIMEOUT.as_secs(),
+                            msg
+                        )));
+                    }
+                    log::debug!("auth is not finished yet: {}", msg);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     async fn get_otp_uri_from_tps(
         &mut self,
         method: &str,
         url: &String,
         token: &String,
     ) -> Result<String, Error> {
-        log::info!("old token is: {token}");
+        log::info!("old token is: {}", utils::redact(token, 0));
         log::info!("please scan the QR code or visit the following link to auth corplink:\n{url}");
         let code = TerminalQrCode::from_bytes(url.as_bytes());
         code.print();
         match method {
-            PLATFORM_LARK | PLATFORM_OIDC => {
-                log::info!("press enter if you finish auth");
-                let stdin = io::stdin();
-                stdin.lines().next();
-                self.check_tps_token(token).await
+            PLATFORM_LARK | PLATFORM_OIDC | PLATFORM_WEIXIN => {
+                match self.conf.tps_poll_interval_secs {
+                    Some(interval) if interval > 0 => {
+                        self.poll_tps_token(token, Duration::from_secs(interval))
+                            .await
+                    }
+                    _ if self.conf.is_non_interactive() => Err(Error::Error(format!(
+                        "can't wait for enter to finish {} auth in non_interactive mode, set tps_poll_interval_secs instead",
+                        method
+                    ))),
+                    _ => {
+                        log::info!("press enter if you finish auth");
+                        let stdin = io::stdin();
+                        stdin.lines().next();
+                        self.check_tps_token(token).await
+                    }
+                }
             }
             _ => {
                 // TODO: add all tps login support
@@ -362,50 +887,66 @@ impl Client {
         }
     }
 
+    // saved seed is dropped when server rejects it, so it's valid as long as it exists
+    fn has_otp_seed(&self) -> bool {
+        self.conf
+            .code
+            .as_deref()
+            .is_some_and(|code| !code.is_empty())
+    }
+
+    // returns None if method is not used, or empty uri if otp provisioning is skipped
     async fn get_otp_uri_by_otp(
         &mut self,
         tps_login: &HashMap<String, RespTpsLoginMethod>,
         method: &String,
-    ) -> Result<String, Error> {
+    ) -> Result<Option<String>, Error> {
         return match self.get_otp_uri(tps_login, method).await {
-            Ok(url) => {
-                if url == "" {
-                    self.request_otp_code().await
+            Ok(Some(url)) => {
+                if url.is_empty() {
+                    if self.has_otp_seed() {
+                        log::info!("2fa seed is already saved, skip otp provisioning");
+                        return Ok(Some(url));
+                    }
+                    self.request_otp_code()
+                        .await
+                        .map(|url| Some(url).filter(|url| !url.is_empty()))
                 } else {
-                    Ok(url)
+                    Ok(Some(url))
                 }
             }
-            Err(e) => Err(e),
+            result => result,
         };
     }
     async fn get_otp_uri(
         &mut self,
         tps_login: &HashMap<String, RespTpsLoginMethod>,
         method: &String,
-    ) -> Result<String, Error> {
+    ) -> Result<Option<String>, Error> {
         if tps_login.contains_key(method) && self.is_platform_or_default(method) {
             log::info!("try to login with third party platform {method}");
             let resp = tps_login.get(method).unwrap();
             return self
                 .get_otp_uri_from_tps(method, &resp.login_url, &resp.token)
-                .await;
+                .await
+                .map(Some);
         }
         match method.as_str() {
             PLATFORM_CORPLINK => {
                 if self.is_platform_or_default(PLATFORM_CORPLINK) {
                     log::info!("try to login with platform {PLATFORM_CORPLINK}");
-                    return self.corplink_login().await;
+                    return self.corplink_login().await.map(Some);
                 }
             }
             PLATFORM_LDAP => {
                 if self.is_platform_or_default(PLATFORM_LDAP) {
                     log::info!("try to login with platform {PLATFORM_LDAP}");
-                    return self.ldap_login().await;
+                    return self.ldap_login().await.map(Some);
                 }
             }
             _ => {}
         }
-        Ok(String::new())
+        Ok(None)
     }
 
     // choose right login method and login
@@ -413,27 +954,63 @@ impl Client {
         let resp = self.get_login_method().await?;
         let tps_login_resp = self.get_tps_login_method().await?;
         let mut tps_login = HashMap::new();
+        let mut methods = match &self.conf.login_methods {
+            // keep order of config and skip the ones server doesn't support
+            Some(login_methods) => {
+                log::info!("login methods of server: {:?}", resp.login_orders);
+                login_methods
+                    .iter()
+                    .filter(|method| resp.login_orders.contains(method))
+                    .cloned()
+                    .collect()
+            }
+            None => resp.login_orders,
+        };
+        let mut choices = methods.clone();
         for resp in tps_login_resp {
+            let allowed = match &self.conf.login_methods {
+                Some(login_methods) => login_methods.contains(&resp.alias),
+                None => true,
+            };
+            if allowed && !choices.contains(&resp.alias) {
+                choices.push(resp.alias.clone());
+            }
             tps_login.insert(resp.alias.clone(), resp);
         }
-        for method in resp.login_orders {
+        let no_platform = self.conf.platform.as_deref().unwrap_or_default().is_empty();
+        if no_platform && io::stdin().is_terminal() && !self.conf.is_non_interactive() {
+            self.pick_login_method(&choices).await;
+            // third party method may not be in login orders
+            if let Some(platform) = &self.conf.platform {
+                if !methods.contains(platform) {
+                    methods.push(platform.clone());
+                }
+            }
+        }
+        for method in methods {
             let otp_uri = self.get_otp_uri_by_otp(&tps_login, &method).await;
             if let Err(e) = otp_uri {
                 log::warn!("failed to login with method {method}: {e}");
                 continue;
             }
-            let otp_uri = otp_uri.unwrap();
+            let otp_uri = match otp_uri.unwrap() {
+                Some(otp_uri) => otp_uri,
+                None => {
+                    log::warn!("failed to login with method {method}");
+                    continue;
+                }
+            };
+            self.change_state(State::Login).await;
             if otp_uri.is_empty() {
-                log::warn!("failed to login with method {method}");
-                continue;
+                return Ok(());
             }
-            self.change_state(State::Login).await;
 
             let url = Url::parse(&otp_uri).unwrap();
             for (k, v) in url.query_pairs() {
                 if k == "secret" {
-                    log::info!("got 2fa token: {}", &v);
+                    log::info!("got 2fa token: {}", utils::redact(&v, 0));
                     self.conf.code = Some(v.to_string());
+                    self.conf.code_owner = Some(self.conf.account());
                     self.conf.save().await;
                     break;
                 }
@@ -450,6 +1027,30 @@ impl Client {
         panic!("no available login method, please provide a valid platform")
     }
 
+    // let user choose from methods of server and save it as platform
+    async fn pick_login_method(&mut self, methods: &[String]) {
+        let mut list = String::from("available login methods:\n\t0. try all of them in order");
+        for (i, method) in methods.iter().enumerate() {
+            list.push_str(&format!("\n\t{}. {}", i + 1, method));
+        }
+        log::info!("{}", list);
+        loop {
+            log::info!("choose login method by number:");
+            let input = utils::read_line().await;
+            match input.trim().parse::<usize>() {
+                Ok(0) => return,
+                Ok(i) if i <= methods.len() => {
+                    let method = methods[i - 1].clone();
+                    log::info!("login method {} is saved as platform", method);
+                    self.conf.platform = Some(method);
+                    self.conf.save().await;
+                    return;
+                }
+                _ => log::warn!("invalid choice {}", input.trim()),
+            }
+        }
+    }
+
     async fn get_login_method(&mut self) -> Result<RespLoginMethod, Error> {
         let resp = self
             .request::<RespLoginMethod>(ApiName::LoginMethod, None)
@@ -482,10 +1083,17 @@ impl Client {
         let mut m = Map::new();
         match platform {
             PLATFORM_LDAP => {
+                if self.conf.password_is_hash.unwrap_or_default() {
+                    return Err(Error::Error(
+                        "ldap requires plain password, please unset password_is_hash".to_string(),
+                    ));
+                }
                 m.insert("platform".to_string(), json!(PLATFORM_LDAP));
             }
             PLATFORM_CORPLINK => {
-                if password.len() != 64 {
+                // guess by length if it's not set explicitly
+                let is_hash = self.conf.password_is_hash.unwrap_or(password.len() == 64);
+                if !is_hash {
                     let mut sha = sha2::Sha256::new();
                     sha.update(password.as_bytes());
                     password = format!("{:x}", sha.finalize());
@@ -522,13 +1130,27 @@ impl Client {
     }
 
     async fn login_with_email(&mut self) -> Result<String, Error> {
-        // tell server to send code to email
-        log::info!("try to request code for email");
-        self.request_email_code().await?;
+        let now = utils::unix_now();
+        match self.conf.email_code_requested_at {
+            Some(requested_at)
+                if now.saturating_sub(requested_at) < EMAIL_CODE_WINDOW.as_secs() =>
+            {
+                log::info!(
+                    "code for email was requested {}s ago, use it instead of requesting a new one",
+                    now - requested_at
+                );
+            }
+            _ => {
+                // tell server to send code to email
+                log::info!("try to request code for email");
+                self.request_email_code().await?;
+                self.conf.email_code_requested_at = Some(now);
+                self.conf.save().await;
+            }
+        }
 
-        log::info!("input your code from email:");
-        let input = utils::read_line().await;
-        let code = input.trim();
+        let code = self.read_code("code from email", EMAIL_CODE_ENV).await?;
+        let code = code.as_str();
         let mut m = Map::new();
         m.insert("forget_password".to_string(), json!(false));
         m.insert("code_type".to_string(), json!("email"));
@@ -538,7 +1160,11 @@ impl Client {
             .request::<RespLogin>(ApiName::LoginEmail, Some(m))
             .await?;
         match resp.code {
-            0 => Ok(resp.data.unwrap().url),
+            0 => {
+                self.conf.email_code_requested_at = None;
+                self.conf.save().await;
+                Ok(resp.data.unwrap().url)
+            }
             _ => Err(Error::Error(format!(
                 "failed to login with email code {}: {}",
                 code,
@@ -547,6 +1173,17 @@ impl Client {
         }
     }
 
+    // drop 2fa seed and login state, so next run will enroll this device again
+    async fn reset_enrollment(&mut self, msg: String) -> Error {
+        self.conf.code = None;
+        self.conf.code_owner = None;
+        self.change_state(State::Init).await;
+        Error::Unregistered(format!(
+            "device is not registered or 2fa seed is invalid: {}, login state is reset, please run again to login and enroll",
+            msg
+        ))
+    }
+
     async fn handle_logout_err(&mut self, msg: String) -> Error {
         self.change_state(State::Init).await;
         Error::Error(format!("operation failed because of logout: {}", msg))
@@ -567,43 +1204,126 @@ impl Client {
         }
     }
 
+    fn save_server_cache(&self, servers: &[RespVpnInfo]) {
+        let file = server_cache_file(&self.conf);
+        let cache = ServerCache {
+            updated_at: utils::unix_now(),
+            servers: servers.to_vec(),
+        };
+        let data = serde_json::to_string(&cache).unwrap();
+        if let Err(err) = utils::write_atomic(&file, data.as_bytes()) {
+            log::warn!("failed to save server cache {}: {}", file.display(), err);
+        }
+    }
+
+    // returns None if cache is missing, broken or stale
+    fn load_server_cache(&self) -> Option<Vec<RespVpnInfo>> {
+        let file = server_cache_file(&self.conf);
+        let cache = match fs::read_to_string(&file) {
+            Ok(data) => match serde_json::from_str::<ServerCache>(&data) {
+                Ok(cache) => cache,
+                Err(err) => {
+                    log::warn!("failed to parse server cache {}: {}", file.display(), err);
+                    return None;
+                }
+            },
+            Err(err) => {
+                log::info!("no server cache {}: {}", file.display(), err);
+                return None;
+            }
+        };
+        let age = utils::unix_now().saturating_sub(cache.updated_at);
+        let ttl = self
+            .conf
+            .server_cache_ttl_secs
+            .unwrap_or(DEFAULT_SERVER_CACHE_TTL_SECS);
+        if age > ttl {
+            log::info!("server cache is {}s old and stale", age);
+            return None;
+        }
+        log::info!("use server cache from {}s ago", age);
+        Some(cache.servers)
+    }
+
+    // ping all servers at the same time, each one with its own copy of vpn api url
+    async fn ping_all_vpn(&self, vpn_info: &[RespVpnInfo], samples: u8) -> Vec<i64> {
+        let pings = vpn_info.iter().map(|vpn| {
+            let mut c = self.clone();
+            let (ip, api_port) = (vpn.ip.clone(), vpn.api_port);
+            async move { c.ping_vpn_samples(ip, api_port, samples).await }
+        });
+        futures::future::join_all(pings).await
+    }
+
+    // all servers with their latency, -1 if the server is unreachable
+    async fn list_vpn_once(&mut self) -> Result<Vec<RespVpnInfo>, Error> {
+        if let Some(vpn_info) = self.listed_servers.take() {
+            return Ok(vpn_info);
+        }
+        let vpn_info = self.list_vpn().await?;
+        self.save_server_cache(&vpn_info);
+        Ok(vpn_info)
+    }
+
+    pub async fn list_vpn_with_latency(&mut self) -> Result<Vec<(RespVpnInfo, i64)>, Error> {
+        let vpn_info = self.list_vpn_once().await?;
+        let samples = self.conf.ping_samples.unwrap_or(1).max(1);
+        let latencies = self.ping_all_vpn(&vpn_info, samples).await;
+        Ok(vpn_info.into_iter().zip(latencies).collect())
+    }
+
     async fn get_first_vpn_by_latency(
         &mut self,
         vpn_info: Vec<RespVpnInfo>,
-    ) -> Option<RespVpnInfo> {
+    ) -> Option<(RespVpnInfo, i64)> {
+        let mut vpn_info = vpn_info;
+        if let Some(max_ping_servers) = self.conf.max_ping_servers {
+            if vpn_info.len() > max_ping_servers {
+                log::info!(
+                    "only ping the first {} of {} servers",
+                    max_ping_servers,
+                    vpn_info.len()
+                );
+                vpn_info.truncate(max_ping_servers);
+            }
+        }
+        let samples = self.conf.ping_samples.unwrap_or(1).max(1);
+        let latencies = self.ping_all_vpn(&vpn_info, samples).await;
         let mut fast_vpn = None;
         let mut min_latency = i64::MAX;
-        for vpn in vpn_info {
-            let latency = self.ping_vpn(vpn.ip.clone(), vpn.api_port).await;
-
+        for (vpn, latency) in vpn_info.into_iter().zip(latencies) {
             log::info!(
                 "server name {}{}",
                 vpn.en_name,
                 match latency {
                     -1 => " timeout".to_string(),
+                    _ if samples > 1 => format!(", average latency {}ms", latency),
                     _ => format!(", latency {}ms", latency),
                 }
             );
             if latency != -1 && latency < min_latency {
-                fast_vpn = Some(vpn);
+                fast_vpn = Some((vpn, latency));
                 min_latency = latency;
             }
         }
         fast_vpn
     }
 
-    async fn get_first_available_vpn(&mut self, vpn_info: Vec<RespVpnInfo>) -> Option<RespVpnInfo> {
+    async fn get_first_available_vpn(
+        &mut self,
+        vpn_info: Vec<RespVpnInfo>,
+    ) -> Option<(RespVpnInfo, i64)> {
         for vpn in vpn_info {
             let latency = self.ping_vpn(vpn.ip.clone(), vpn.api_port.clone()).await;
             if latency != -1 {
-                return Some(vpn);
+                return Some((vpn, latency));
             }
         }
         None
     }
 
-    // ping vpn and return latency in ms. Will return -1 on error
-    async fn ping_vpn(&mut self, ip: String, api_port: u16) -> i64 {
+    // point vpn apis to the server and copy cookies for it
+    fn set_vpn_server(&mut self, ip: &str, api_port: u16) {
         {
             // config cookie
             let mut cookie = self.cookie.lock().unwrap();
@@ -616,17 +1336,42 @@ impl Client {
                     cookies.push(c.clone());
                 }
             }
-            url.set_host(Some(ip.as_str())).unwrap();
+            url.set_host(Some(ip)).unwrap();
             url.set_port(Some(api_port)).unwrap();
             for c in cookies {
                 let mut c = cookie::Cookie::new(c.name().to_string(), c.value().to_string());
-                c.set_domain(ip.clone());
+                c.set_domain(ip.to_string());
                 let c = Cookie::try_from_raw_cookie(&c, &url.clone()).unwrap();
                 cookie.insert(c, &url.clone()).unwrap();
             }
             self.api_url.vpn_param.url = url.to_string().trim_end_matches('/').to_string();
         }
         self.save_cookie();
+    }
+
+    // ping vpn several times and return the average latency without the max one, -1 if all fail
+    async fn ping_vpn_samples(&mut self, ip: String, api_port: u16, samples: u8) -> i64 {
+        let mut latencies = Vec::new();
+        for _ in 0..samples {
+            let latency = self.ping_vpn(ip.clone(), api_port).await;
+            if latency != -1 {
+                latencies.push(latency);
+            }
+        }
+        if latencies.is_empty() {
+            return -1;
+        }
+        latencies.sort();
+        if latencies.len() > 1 {
+            // one retransmit makes a sample much slower
+            latencies.pop();
+        }
+        latencies.iter().sum::<i64>() / latencies.len() as i64
+    }
+
+    // ping vpn and return latency in ms. Will return -1 on error
+    async fn ping_vpn(&mut self, ip: String, api_port: u16) -> i64 {
+        self.set_vpn_server(&ip, api_port);
         let req_start = Utc::now().timestamp_millis();
         let result = self.request::<String>(ApiName::PingVPN, None).await;
         let req_end = Utc::now().timestamp_millis();
@@ -649,6 +1394,25 @@ impl Client {
         -1
     }
 
+    // read code from env or stdin, stdin is never read in non interactive mode
+    async fn read_code(&self, name: &str, env: &str) -> Result<String, Error> {
+        if let Ok(code) = std::env::var(env) {
+            let code = code.trim();
+            if !code.is_empty() {
+                log::info!("use {} from {}", name, env);
+                return Ok(code.to_string());
+            }
+        }
+        if self.conf.is_non_interactive() {
+            return Err(Error::Error(format!(
+                "{} is required but stdin is not read in non_interactive mode, set it with {}",
+                name, env
+            )));
+        }
+        log::info!("input your {}:", name);
+        Ok(utils::read_line().await.trim().to_string())
+    }
+
     async fn fetch_peer_info(&mut self, public_key: &String) -> Result<RespWgInfo, Error> {
         let mut otp = String::new();
         if let Some(code) = &self.conf.code {
@@ -659,14 +1423,13 @@ impl Client {
                 otp = format!("{:06}", raw_otp.code);
                 log::info!(
                     "2fa code generated: {}, {} seconds left",
-                    &otp,
+                    utils::redact(&otp, 2),
                     raw_otp.secs_left
                 );
             }
         }
         if otp.is_empty() {
-            log::info!("input your 2fa code:");
-            otp = utils::read_line().await;
+            otp = self.read_code("2fa code", OTP_ENV).await?;
         }
         let mut m = Map::new();
         m.insert("public_key".to_string(), json!(public_key));
@@ -677,17 +1440,208 @@ impl Client {
         match resp.code {
             0 => Ok(resp.data.unwrap()),
             101 => Err(self.handle_logout_err(resp.message.unwrap()).await),
-            _ => Err(Error::Error(format!(
-                "failed to fetch peer info with error {}: {}",
-                resp.code,
-                resp.message.unwrap()
+            _ => {
+                let msg = format!(
+                    "failed to fetch peer info with error {}: {}",
+                    resp.code,
+                    resp.message.unwrap()
+                );
+                let failover_codes = self.conf.failover_codes.clone().unwrap_or_default();
+                let otp_error_codes = self.conf.otp_error_codes.clone().unwrap_or_default();
+                let unregistered_codes = self.conf.unregistered_codes.clone().unwrap_or_default();
+                if failover_codes.contains(&resp.code) {
+                    Err(Error::Unavailable(msg))
+                } else if unregistered_codes.contains(&resp.code) {
+                    Err(self.reset_enrollment(msg).await)
+                } else if otp_error_codes.contains(&resp.code) {
+                    Err(Error::InvalidOtp(msg))
+                } else {
+                    log::info!(
+                        "add {} to failover_codes if it means the gateway is unavailable",
+                        resp.code
+                    );
+                    Err(Error::Error(msg))
+                }
+            }
+        }
+    }
+
+    // retry with new 2fa code if it's rejected, time offset is updated by the failed request
+    async fn fetch_peer_info_with_otp_retry(
+        &mut self,
+        public_key: &String,
+    ) -> Result<RespWgInfo, Error> {
+        let mut retry = 0;
+        loop {
+            match self.fetch_peer_info(public_key).await {
+                Err(Error::InvalidOtp(msg)) if retry < OTP_RETRY => {
+                    retry += 1;
+                    log::warn!("{}, retry with new 2fa code", msg);
+                }
+                // codes from saved seed keep being rejected, the seed must be invalid
+                Err(Error::InvalidOtp(msg))
+                    if self
+                        .conf
+                        .code
+                        .as_deref()
+                        .is_some_and(|code| !code.is_empty()) =>
+                {
+                    return Err(self.reset_enrollment(msg).await);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn select_vpn(
+        &mut self,
+        vpn_info: Vec<RespVpnInfo>,
+    ) -> Result<Option<(RespVpnInfo, Option<i64>)>, Error> {
+        let mut vpn_info = vpn_info;
+        if self.conf.sticky_server.unwrap_or_default() {
+            if let Some(last_server) = &self.conf.last_server {
+                if let Some(vpn) = vpn_info.iter().find(|vpn| &vpn.en_name == last_server) {
+                    let latency = self.ping_vpn(vpn.ip.clone(), vpn.api_port).await;
+                    if latency != -1 {
+                        log::info!(
+                            "server {} is used last time, latency {}ms",
+                            vpn.en_name,
+                            latency
+                        );
+                        return Ok(Some((vpn.clone(), Some(latency))));
+                    }
+                    log::info!("server {} used last time is unreachable", vpn.en_name);
+                } else {
+                    log::info!("server {} used last time is not available", last_server);
+                }
+            }
+        }
+        if let Some(priority) = self.conf.server_priority.clone() {
+            // servers in priority list are preferred as long as they are reachable
+            for server_name in &priority {
+                let vpn = match vpn_info.iter().find(|vpn| &vpn.en_name == server_name) {
+                    Some(vpn) => vpn.clone(),
+                    None => continue,
+                };
+                let latency = self.ping_vpn(vpn.ip.clone(), vpn.api_port).await;
+                if latency != -1 {
+                    log::info!(
+                        "server {} is preferred by priority, latency {}ms",
+                        vpn.en_name,
+                        latency
+                    );
+                    return Ok(Some((vpn, Some(latency))));
+                }
+                log::info!("server {} in priority list is unreachable", vpn.en_name);
+            }
+            vpn_info.retain(|vpn| !priority.contains(&vpn.en_name));
+        }
+        let strategy = self
+            .conf
+            .vpn_select_strategy_override
+            .clone()
+            .or(self.conf.vpn_select_strategy.clone());
+        let vpn = match strategy {
+            Some(strategy) => match strategy.as_str() {
+                STRATEGY_LATENCY => match self.get_first_vpn_by_latency(vpn_info.clone()).await {
+                    Some((vpn, latency)) => Some((vpn, Some(latency))),
+                    None => {
+                        // ping api may be blocked while wg still works
+                        log::warn!(
+                            "latency of all servers is unavailable, fallback to the first one"
+                        );
+                        vpn_info.into_iter().next().map(|vpn| (vpn, None))
+                    }
+                },
+                STRATEGY_DEFAULT => self
+                    .get_first_available_vpn(vpn_info)
+                    .await
+                    .map(|(vpn, latency)| (vpn, Some(latency))),
+                STRATEGY_FIRST => vpn_info.into_iter().next().map(|vpn| (vpn, None)),
+                _ => return Err(Error::Error("unsupported strategy".to_string())),
+            },
+            None => self
+                .get_first_available_vpn(vpn_info)
+                .await
+                .map(|(vpn, latency)| (vpn, Some(latency))),
+        };
+        Ok(vpn)
+    }
+
+    // select a server, try next one if gateway of it can't be warmed up
+    async fn pick_vpn(
+        &mut self,
+        filtered_vpn: &mut Vec<RespVpnInfo>,
+    ) -> Result<(RespVpnInfo, Option<i64>), Error> {
+        loop {
+            let (vpn, latency) = match self.select_vpn(filtered_vpn.clone()).await? {
+                Some(selected) => selected,
+                None => return Err(Error::Error("no vpn available".to_string())),
+            };
+            if !self.conf.prewarm_gateway.unwrap_or_default() {
+                return Ok((vpn, latency));
+            }
+            let vpn_addr = utils::join_host_port(&vpn.ip, vpn.vpn_port);
+            match prewarm_gateway(&vpn_addr, vpn.protocol_mode).await {
+                Ok(_) => {
+                    log::info!("gateway {} is warmed up", vpn_addr);
+                    return Ok((vpn, latency));
+                }
+                Err(err) => {
+                    log::warn!(
+                        "failed to prewarm gateway of {}: {}, try next server",
+                        vpn.en_name,
+                        err
+                    );
+                    filtered_vpn.retain(|v| v.id != vpn.id);
+                }
+            }
+        }
+    }
+
+    // mismatched keypair is accepted by server but never handshakes
+    fn check_keypair(&self) -> Result<(), Error> {
+        let public_key = self.conf.public_key.as_ref().unwrap();
+        let private_key = self.conf.private_key.as_ref().unwrap();
+        match utils::gen_public_key_from_private(private_key) {
+            Ok(derived) if &derived == public_key => Ok(()),
+            Ok(derived) => Err(Error::Error(format!(
+                "public_key {} does not match private_key, which derives {}, please fix the config or run reset-all --regen-keys",
+                public_key, derived
             ))),
+            // error contains the private key, so don't print it
+            Err(_) => Err(Error::Error(
+                "private_key is not a valid base64 encoded 32 bytes key".to_string(),
+            )),
         }
     }
 
+    // skip the server in following connect_vpn calls
+    pub fn exclude_server(&mut self, server_name: &str) {
+        self.excluded_servers.push(server_name.to_string());
+    }
+
     pub async fn connect_vpn(&mut self) -> Result<WgConf, Error> {
-        let vpn_info = self.list_vpn().await?;
+        self.check_keypair()?;
+        if self.conf.use_cached_servers {
+            if let Some(vpn_info) = self.load_server_cache() {
+                match self.connect_vpn_with(vpn_info).await {
+                    Ok(wg_conf) => return Ok(wg_conf),
+                    Err(e @ Error::Unregistered(_)) => return Err(e),
+                    Err(e) => {
+                        log::warn!(
+                            "failed to connect with cached servers: {}, list servers again",
+                            e
+                        )
+                    }
+                }
+            }
+        }
+        let vpn_info = self.list_vpn_once().await?;
+        self.connect_vpn_with(vpn_info).await
+    }
 
+    async fn connect_vpn_with(&mut self, vpn_info: Vec<RespVpnInfo>) -> Result<WgConf, Error> {
         log::info!(
             "found {} vpn(s), details: {:?}",
             vpn_info.len(),
@@ -696,7 +1650,13 @@ impl Client {
                 .map(|i| i.en_name.clone())
                 .collect::<Vec<String>>()
         );
-        let filtered_vpn = vpn_info
+        let server_regex = match &self.conf.vpn_server_regex {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| {
+                Error::Error(format!("invalid vpn_server_regex {}: {}", pattern, e))
+            })?),
+            None => None,
+        };
+        let mut filtered_vpn: Vec<RespVpnInfo> = vpn_info
             .into_iter()
             .filter(|vpn| {
                 if let Some(server_name) = self.conf.vpn_server_name.clone() {
@@ -705,6 +1665,16 @@ impl Client {
                         return false;
                     }
                 }
+                if self.excluded_servers.contains(&vpn.en_name) {
+                    log::info!("skip {}, it failed to handshake", vpn.en_name);
+                    return false;
+                }
+                if let Some(regex) = &server_regex {
+                    if !regex.is_match(&vpn.en_name) {
+                        log::info!("skip {}, expect to match {}", vpn.en_name, regex);
+                        return false;
+                    }
+                }
                 true
             })
             .filter(|vpn| {
@@ -716,6 +1686,14 @@ impl Client {
                 match mode {
                     "udp" => true,
                     "tcp" => true,
+                    _ if self.conf.allow_unknown_protocol.unwrap_or_default() => {
+                        log::warn!(
+                            "server {} uses unknown protocol mode {}, try it as udp wg, it may not work",
+                            vpn.en_name,
+                            vpn.protocol_mode
+                        );
+                        true
+                    }
                     _ => {
                         log::info!(
                             "server name {} is not support {} wg for now",
@@ -728,54 +1706,158 @@ impl Client {
             })
             .collect();
 
-        let vpn = match self.conf.vpn_select_strategy.clone() {
-            Some(strategy) => match strategy.as_str() {
-                STRATEGY_LATENCY => self.get_first_vpn_by_latency(filtered_vpn).await,
-                STRATEGY_DEFAULT => self.get_first_available_vpn(filtered_vpn).await,
-                _ => return Err(Error::Error("unsupported strategy".to_string())),
-            },
-            None => self.get_first_available_vpn(filtered_vpn).await,
-        };
-
-        let vpn = match vpn {
-            Some(ref vpn) => vpn,
-            None => return Err(Error::Error("no vpn available".to_string())),
+        let (vpn, latency, wg_info) = loop {
+            let (vpn, latency) = self.pick_vpn(&mut filtered_vpn).await?;
+            let vpn_addr = utils::join_host_port(&vpn.ip, vpn.vpn_port);
+            log::info!("try connect to {}, address {}", vpn.en_name, vpn_addr);
+
+            // vpn apis may point to another server after ping
+            self.set_vpn_server(&vpn.ip, vpn.api_port);
+
+            let key = self.conf.public_key.clone().unwrap();
+            log::info!("try to get wg conf from remote");
+            match self.fetch_peer_info_with_otp_retry(&key).await {
+                Ok(wg_info) => break (vpn, latency, wg_info),
+                Err(Error::Unavailable(msg)) => {
+                    log::warn!("{}, try next server", msg);
+                    filtered_vpn.retain(|v| v.id != vpn.id);
+                }
+                Err(e) => return Err(e),
+            }
         };
-        let vpn_addr = format!("{}:{}", vpn.ip, vpn.vpn_port);
-        log::info!(
-            "try connect to {}, address {}",
-            vpn.en_name,
-            vpn_addr
-        );
-
-        let key = self.conf.public_key.clone().unwrap();
-        log::info!("try to get wg conf from remote");
-        let wg_info = self.fetch_peer_info(&key).await?;
+        let vpn_addr = utils::join_host_port(&vpn.ip, vpn.vpn_port);
+        if let Some(cidr) = &self.conf.expected_address_cidr {
+            match utils::ip_in_cidr(&wg_info.ip, cidr) {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(Error::Error(format!(
+                        "assigned address {} is not in expected cidr {}, refuse to connect",
+                        wg_info.ip, cidr
+                    )))
+                }
+                Err(e) => {
+                    return Err(Error::Error(format!(
+                        "failed to check assigned address {} with expected cidr {}: {}",
+                        wg_info.ip, cidr, e
+                    )))
+                }
+            }
+        }
         let mtu = wg_info.setting.vpn_mtu;
-        let dns = wg_info.setting.vpn_dns;
+        // backup dns gives redundancy when the primary one is flaky
+        let mut dns: Vec<String> = Vec::new();
+        for server in [&wg_info.setting.vpn_dns, &wg_info.setting.vpn_dns_backup] {
+            let server = server.trim();
+            if !server.is_empty() && !dns.iter().any(|d| d == server) {
+                dns.push(server.to_string());
+            }
+        }
         let peer_key = wg_info.public_key;
+        if let Some(expected_peer_key) = &self.conf.expected_peer_key {
+            if &peer_key != expected_peer_key {
+                return Err(Error::Error(format!(
+                    "peer key {} from server is not the expected {}, refuse to connect",
+                    peer_key, expected_peer_key
+                )));
+            }
+        }
         let public_key = self.conf.public_key.clone().unwrap();
         let private_key = self.conf.private_key.clone().unwrap();
-        let route = wg_info.setting.vpn_route_split;
+        let routes = match &self.conf.routes {
+            Some(routes) => routes.clone(),
+            None => wg_info.setting.vpn_route_split.clone(),
+        };
+        let routes = match self
+            .conf
+            .route_family
+            .as_deref()
+            .unwrap_or(ROUTE_FAMILY_BOTH)
+        {
+            ROUTE_FAMILY_BOTH => routes,
+            ROUTE_FAMILY_V4 => routes.into_iter().filter(|r| !r.contains(':')).collect(),
+            ROUTE_FAMILY_V6 if wg_info.ipv6.is_empty() => {
+                return Err(Error::Error(
+                    "route_family is v6 but server assigns no ipv6 address".to_string(),
+                ))
+            }
+            ROUTE_FAMILY_V6 => routes.into_iter().filter(|r| r.contains(':')).collect(),
+            family => return Err(Error::Error(format!("unsupported route_family {}", family))),
+        };
+        let allowed_ips = match &self.conf.allowed_ips {
+            Some(allowed_ips) => allowed_ips.clone(),
+            None => wg_info.setting.vpn_route_split,
+        };
+
+        // without domains, all queries go to vpn dns
+        let dns_domains = if self.conf.split_dns.unwrap_or_default()
+            || self.conf.dns_domain_allowlist.is_some()
+        {
+            filter_dns_domains(
+                wg_info
+                    .setting
+                    .vpn_dns_domain_split
+                    .clone()
+                    .unwrap_or_default(),
+                self.conf.dns_domain_allowlist.as_deref(),
+            )
+        } else {
+            Vec::new()
+        };
+        let mask = match self.conf.address_prefix_override {
+            Some(prefix) => {
+                if prefix > 32 {
+                    return Err(Error::Error(format!(
+                        "invalid address_prefix_override {}, should be in 0-32",
+                        prefix
+                    )));
+                }
+                log::info!(
+                    "override address mask {} from server with {}",
+                    wg_info.ip_mask,
+                    prefix
+                );
+                prefix as u32
+            }
+            None => wg_info.ip_mask.parse::<u32>().unwrap(),
+        };
+
+        let protocol = match vpn.protocol_mode {
+            // tcp
+            1 => 1,
+            // udp, unknown modes are also tried as udp
+            _ => 0,
+        };
+        let persistent_keepalive = match &self.conf.wg_keepalive_interval {
+            Some(interval) => interval.of(protocol),
+            None => DEFAULT_WG_KEEPALIVE_INTERVAL,
+        };
 
         // corplink config
         let wg_conf = WgConf {
             address: wg_info.ip,
-            mask: wg_info.ip_mask.parse::<u32>().unwrap(),
+            address6: wg_info.ipv6,
+            mask,
             peer_address: vpn_addr,
             mtu,
             public_key,
             private_key,
             peer_key,
-            route,
+            allowed_ips,
+            routes,
+            route_mode: ROUTE_MODE_SPLIT.to_string(),
+            persistent_keepalive,
             dns,
-            protocol: match vpn.protocol_mode {
-                // tcp
-                1 => 1,
-                // udp
-                _ => 0,
-            },
+            dns_domains,
+            server_name: vpn.en_name.clone(),
+            latency,
+            protocol,
         };
+        if self.conf.sticky_server.unwrap_or_default()
+            && self.conf.last_server.as_ref() != Some(&vpn.en_name)
+        {
+            self.conf.last_server = Some(vpn.en_name.clone());
+            self.conf.save().await;
+        }
         Ok(wg_conf)
     }
 
@@ -789,17 +1871,14 @@ impl Client {
                     return;
                 }
             }
-            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let jitter_percent = self.conf.interval_jitter_percent.unwrap_or_default();
+            let interval = utils::jitter(Duration::from_secs(interval), jitter_percent);
+            tokio::time::sleep(interval).await;
         }
     }
 
     pub async fn report_vpn_status(&mut self, conf: &WgConf) -> Result<(), Error> {
-        let mut m = Map::new();
-        m.insert("ip".to_string(), json!(conf.address));
-        m.insert("public_key".to_string(), json!(conf.public_key));
-        m.insert("mode".to_string(), json!("Split"));
-        m.insert("type".to_string(), json!("100"));
-
+        let m = vpn_report(conf, REPORT_TYPE_KEEPALIVE);
         let resp = self
             .request::<Map<String, Value>>(ApiName::KeepAliveVPN, Some(m))
             .await?;
@@ -814,11 +1893,7 @@ impl Client {
     }
 
     pub async fn disconnect_vpn(&mut self, wg_conf: &WgConf) -> Result<(), Error> {
-        let mut m = Map::new();
-        m.insert("ip".to_string(), json!(wg_conf.address));
-        m.insert("public_key".to_string(), json!(wg_conf.public_key));
-        m.insert("mode".to_string(), json!("Split"));
-        m.insert("type".to_string(), json!("101"));
+        let m = vpn_report(wg_conf, REPORT_TYPE_DISCONNECT);
         let resp = self
             .request::<Map<String, Value>>(ApiName::DisconnectVPN, Some(m))
             .await?;
@@ -832,3 +1907,39 @@ impl Client {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domains(domains: &[&str]) -> Vec<String> {
+        domains.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    #[test]
+    fn filter_dns_domains_with_allowlist() {
+        let all = domains(&[
+            "~example.com",
+            "git.example.com",
+            "badexample.com",
+            "corp.internal",
+            "bad domain",
+            "-bad.example.com",
+        ]);
+        assert_eq!(
+            filter_dns_domains(all.clone(), None),
+            domains(&[
+                "example.com",
+                "git.example.com",
+                "badexample.com",
+                "corp.internal"
+            ])
+        );
+        let allowlist = domains(&["example.com"]);
+        assert_eq!(
+            filter_dns_domains(all.clone(), Some(&allowlist)),
+            domains(&["example.com", "git.example.com"])
+        );
+        assert!(filter_dns_domains(all, Some(&[])).is_empty());
+    }
+}
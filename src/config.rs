@@ -1,20 +1,34 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::path::Path;
 use tokio::fs;
 
 use serde::{Deserialize, Serialize};
 
 use crate::state::State;
 use crate::utils;
+#[cfg(target_os = "macos")]
+use crate::wg;
 
 const DEFAULT_DEVICE_NAME: &str = "DollarOS";
 const DEFAULT_INTERFACE_NAME: &str = "corplink";
+pub const DEFAULT_INITIAL_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_UAPI_READY_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_SERVER_CACHE_TTL_SECS: u64 = 86400;
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_INTERFACE_UP_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_WG_KEEPALIVE_INTERVAL: u64 = 10;
+pub const DEFAULT_TUNNEL_KEEPALIVE_INTERVAL_SECS: u64 = 20;
+pub const DEFAULT_STATSD_INTERVAL_SECS: u64 = 10;
+const DEFAULT_KEEPALIVE_INTERVAL_SEC: u64 = 60;
 
 pub const PLATFORM_LDAP: &str = "ldap";
 pub const PLATFORM_CORPLINK: &str = "feilian";
 pub const PLATFORM_OIDC: &str = "OIDC";
 // aka feishu
 pub const PLATFORM_LARK: &str = "lark";
-#[allow(dead_code)]
+// aka wechat work
 pub const PLATFORM_WEIXIN: &str = "weixin";
 // aka dingding
 #[allow(dead_code)]
@@ -25,6 +39,25 @@ pub const PLATFORM_AAD: &str = "aad";
 
 pub const STRATEGY_LATENCY: &str = "latency";
 pub const STRATEGY_DEFAULT: &str = "default";
+pub const STRATEGY_FIRST: &str = "first";
+
+pub const INTERFACE_REUSE: &str = "reuse";
+pub const INTERFACE_RECREATE: &str = "recreate";
+pub const INTERFACE_FAIL: &str = "fail";
+
+#[cfg(target_os = "macos")]
+pub const MACOS_DNS_NETWORKSETUP: &str = "networksetup";
+#[cfg(target_os = "macos")]
+pub const MACOS_DNS_SCUTIL: &str = "scutil";
+
+pub const ROUTE_FAMILY_BOTH: &str = "both";
+pub const ROUTE_FAMILY_V4: &str = "v4";
+pub const ROUTE_FAMILY_V6: &str = "v6";
+
+pub const LOG_TARGET_STDOUT: &str = "stdout";
+#[cfg(unix)]
+pub const LOG_TARGET_SYSLOG: &str = "syslog";
+pub const LOG_TARGET_FILE: &str = "file";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -33,6 +66,8 @@ pub struct Config {
     pub password: Option<String>,
     pub platform: Option<String>,
     pub code: Option<String>,
+    // account which the 2fa seed in code belongs to
+    pub code_owner: Option<String>,
     pub device_name: Option<String>,
     pub device_id: Option<String>,
     pub public_key: Option<String>,
@@ -44,8 +79,85 @@ pub struct Config {
     pub conf_file: Option<String>,
     pub state: Option<State>,
     pub vpn_server_name: Option<String>,
+    pub vpn_server_regex: Option<String>,
     pub vpn_select_strategy: Option<String>,
     pub use_vpn_dns: Option<bool>,
+    pub require_keys: Option<bool>,
+    pub expected_address_cidr: Option<String>,
+    pub prewarm_gateway: Option<bool>,
+    pub on_existing_interface: Option<String>,
+    pub verify_cleanup: Option<bool>,
+    pub disable_handshake_watcher: Option<bool>,
+    pub initial_handshake_timeout_secs: Option<u64>,
+    pub allowed_ips: Option<Vec<String>>,
+    pub routes: Option<Vec<String>>,
+    pub address_prefix_override: Option<u8>,
+    pub interval_jitter_percent: Option<u32>,
+    pub expected_peer_key: Option<String>,
+    pub log_target: Option<String>,
+    pub log_file: Option<String>,
+    pub max_ping_servers: Option<usize>,
+    pub dns_domain_allowlist: Option<Vec<String>>,
+    pub audit_log: Option<String>,
+    pub uapi_ready_timeout_secs: Option<u64>,
+    pub dns_apply_delay_ms: Option<u64>,
+    pub server_cache_ttl_secs: Option<u64>,
+    pub shutdown_timeout_secs: Option<u64>,
+    pub route_metric: Option<u32>,
+    // when the last code is sent to email, cleared after login
+    pub email_code_requested_at: Option<u64>,
+    pub custom_headers: Option<HashMap<String, String>>,
+    pub control_listen: Option<String>,
+    pub request_id_header: Option<String>,
+    pub route_family: Option<String>,
+    pub server_priority: Option<Vec<String>>,
+    pub failover_codes: Option<Vec<i32>>,
+    pub otp_error_codes: Option<Vec<i32>>,
+    pub unregistered_codes: Option<Vec<i32>>,
+    pub export_dns_hooks: Option<bool>,
+    pub interface_up_timeout_secs: Option<u64>,
+    pub keepalive_interval_sec: Option<u64>,
+    pub ping_timeout_ms: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub pid_file: Option<String>,
+    pub allow_unknown_protocol: Option<bool>,
+    pub tps_poll_interval_secs: Option<u64>,
+    pub password_is_hash: Option<bool>,
+    pub server_public_key: Option<String>,
+    pub accept_invalid_certs: Option<bool>,
+    pub self_signed_cert: Option<String>,
+    pub wg_keepalive_interval: Option<WgKeepaliveInterval>,
+    pub login_methods: Option<Vec<String>>,
+    pub ping_samples: Option<u8>,
+    pub flush_dns: Option<bool>,
+    pub handshake_failover_servers: Option<u32>,
+    pub tunnel_keepalive_target: Option<String>,
+    pub tunnel_keepalive_interval_secs: Option<u64>,
+    pub statsd_target: Option<String>,
+    pub statsd_interval_secs: Option<u64>,
+    pub macos_dns_backend: Option<String>,
+    pub dns_verify_host: Option<String>,
+    pub reconnect_on_wake: Option<bool>,
+    pub status_file: Option<String>,
+    pub kill_switch: Option<bool>,
+    pub split_dns: Option<bool>,
+    pub non_interactive: Option<bool>,
+    pub report_os: Option<String>,
+    pub report_os_version: Option<String>,
+    pub post_up: Option<Vec<String>>,
+    pub pre_down: Option<Vec<String>>,
+    pub hook_strict: Option<bool>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+    pub sticky_server: Option<bool>,
+    // en_name of the server connected last time, used by sticky_server
+    pub last_server: Option<String>,
+    #[serde(skip)]
+    pub vpn_select_strategy_override: Option<String>,
+    #[serde(skip)]
+    pub use_cached_servers: bool,
+    #[serde(skip)]
+    pub non_interactive_override: bool,
 }
 
 impl fmt::Display for Config {
@@ -57,9 +169,21 @@ impl fmt::Display for Config {
 
 impl Config {
     pub async fn from_file(file: &str) -> Config {
+        let (conf, update_conf) = Config::load(file).await;
+        if update_conf {
+            conf.save().await;
+        }
+        conf
+    }
+
+    // read config and fill defaults without saving it, returns true if defaults are filled
+    // so it can be saved after the instance lock is held
+    pub async fn load(file: &str) -> (Config, bool) {
         let conf_str = fs::read_to_string(file)
             .await
             .unwrap_or_else(|e| panic!("failed to read config file {}: {}", file, e));
+        // config contains password, wg private key and 2fa secret
+        utils::check_private_file(Path::new(file));
 
         let mut conf: Config = serde_json::from_str(&conf_str[..])
             .unwrap_or_else(|e| panic!("failed to parse config file {}: {}", file, e));
@@ -70,6 +194,30 @@ impl Config {
             conf.interface_name = Some(DEFAULT_INTERFACE_NAME.to_string());
             update_conf = true;
         }
+        #[cfg(target_os = "macos")]
+        {
+            let name = conf.interface_name.clone().unwrap();
+            if !wg::is_valid_interface_name(&name) {
+                // keep the chosen name so that cookie and socket paths stay the same across runs
+                let free = wg::free_interface_name().unwrap_or_else(|| {
+                    panic!(
+                        "interface name {} is invalid on macos, set interface_name in {} to utunN (N <= 32767)",
+                        name, file
+                    )
+                });
+                log::warn!(
+                    "interface name {} is invalid on macos, it must be utunN (N <= 32767), use {} instead",
+                    name,
+                    free
+                );
+                conf.interface_name = Some(free);
+                update_conf = true;
+            }
+        }
+        if conf.keepalive_interval_sec.is_none() {
+            conf.keepalive_interval_sec = Some(DEFAULT_KEEPALIVE_INTERVAL_SEC);
+            update_conf = true;
+        }
         if conf.device_name.is_none() {
             conf.device_name = Some(DEFAULT_DEVICE_NAME.to_string());
             update_conf = true;
@@ -94,22 +242,88 @@ impl Config {
                 }
             },
             None => {
+                if conf.require_keys.unwrap_or_default() {
+                    panic!(
+                        "no private key found in config file {} and require_keys is set, please provide your own keypair",
+                        file
+                    );
+                }
                 // no key exists, generate new
                 let (public_key, private_key) = utils::gen_wg_keypair();
                 (conf.public_key, conf.private_key) = (Some(public_key), Some(private_key));
                 update_conf = true;
             }
         }
-        if update_conf {
-            conf.save().await;
+        if let Some(server) = &conf.server {
+            let normalized = utils::normalize_server_url(server).unwrap_or_else(|e| {
+                panic!("invalid server {} in config file {}: {}", server, file, e)
+            });
+            if &normalized != server {
+                log::info!("normalize server {} to {}", server, normalized);
+                conf.server = Some(normalized);
+                update_conf = true;
+            }
         }
-        conf
+        if conf.code.as_deref().is_some_and(|code| !code.is_empty()) {
+            let owner = conf.account();
+            match &conf.code_owner {
+                Some(code_owner) if code_owner != &owner => {
+                    // config is copied from or changed to another account, never use others' seed
+                    log::warn!(
+                        "2fa seed belongs to {}, drop it and login again as {}",
+                        code_owner,
+                        owner
+                    );
+                    conf.code = None;
+                    conf.code_owner = None;
+                    conf.state = Some(State::Init);
+                    update_conf = true;
+                }
+                Some(_) => {}
+                None => {
+                    conf.code_owner = Some(owner);
+                    update_conf = true;
+                }
+            }
+        }
+        (conf, update_conf)
+    }
+
+    // never read from stdin, for systemd and containers
+    pub fn is_non_interactive(&self) -> bool {
+        self.non_interactive_override || self.non_interactive.unwrap_or_default()
+    }
+
+    // identify the account with username and company
+    pub fn account(&self) -> String {
+        format!("{}@{}", self.username, self.company_name)
     }
 
     pub async fn save(&self) {
         let file = self.conf_file.as_ref().unwrap();
         let data = format!("{}", &self);
-        fs::write(file, data).await.unwrap();
+        utils::write_private_atomic(Path::new(file), data.as_bytes()).unwrap();
+    }
+}
+
+// persistent keepalive of wg, one value for all or one for each protocol
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum WgKeepaliveInterval {
+    All(u64),
+    PerProtocol { udp: Option<u64>, tcp: Option<u64> },
+}
+
+impl WgKeepaliveInterval {
+    // protocol is the one in wg conf, 1 for tcp and 0 for udp
+    pub fn of(&self, protocol: i32) -> u64 {
+        match self {
+            WgKeepaliveInterval::All(interval) => *interval,
+            WgKeepaliveInterval::PerProtocol { udp, tcp } => {
+                let interval = if protocol == 1 { tcp } else { udp };
+                interval.unwrap_or(DEFAULT_WG_KEEPALIVE_INTERVAL)
+            }
+        }
     }
 }
 
@@ -117,17 +331,66 @@ impl Config {
 pub struct WgConf {
     // standard wg conf
     pub address: String,
+    pub address6: String,
     pub mask: u32,
     pub peer_address: String,
     pub mtu: u32,
     pub public_key: String,
     pub private_key: String,
     pub peer_key: String,
-    pub route: Vec<String>,
+    pub allowed_ips: Vec<String>,
+    pub routes: Vec<String>,
+    pub route_mode: String,
+    pub persistent_keepalive: u64,
 
     // extent confs
-    pub dns: String,
+    pub dns: Vec<String>,
+    pub dns_domains: Vec<String>,
+    pub server_name: String,
+    // latency in ms measured when selecting server
+    pub latency: Option<i64>,
 
     // corplink confs
     pub protocol: i32,
 }
+
+impl WgConf {
+    pub fn protocol_name(&self) -> &str {
+        match self.protocol {
+            1 => "tcp",
+            _ => "udp",
+        }
+    }
+
+    // endpoint with latency if it's measured
+    pub fn endpoint_detail(&self) -> String {
+        match self.latency {
+            Some(latency) => format!("{} latency {}ms", self.peer_address, latency),
+            None => self.peer_address.clone(),
+        }
+    }
+
+    // one line summary of the tunnel
+    pub fn summary(&self) -> String {
+        let mut address = format!("{}/{}", self.address, self.mask);
+        if !self.address6.is_empty() {
+            address = format!("{},{}", address, self.address6);
+        }
+        let latency = match self.latency {
+            Some(latency) => format!(" latency {}ms", latency),
+            None => String::new(),
+        };
+        format!(
+            "connected to {}{} endpoint {} address {} dns {} mtu {} protocol {} mode {} with {} route(s)",
+            self.server_name,
+            latency,
+            self.peer_address,
+            address,
+            self.dns.join(","),
+            self.mtu,
+            self.protocol_name(),
+            self.route_mode.to_lowercase(),
+            self.routes.len()
+        )
+    }
+}
@@ -0,0 +1,160 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::config::WgConf;
+use crate::utils;
+use crate::wg::UAPIClient;
+
+pub const UNIX_PREFIX: &str = "unix:";
+const MAX_REQUEST_SIZE: usize = 8192;
+// slow or idle clients are dropped, so they can't hold the connection forever
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Context {
+    uapi: UAPIClient,
+    wg_conf: WgConf,
+    connected_at: u64,
+}
+
+// serve /healthz /status and /metrics on host:port or unix:/path/to.sock
+pub async fn serve(listen: &str, name: &str, wg_conf: &WgConf) -> io::Result<()> {
+    let ctx = Arc::new(Context {
+        uapi: UAPIClient {
+            name: name.to_string(),
+            jitter_percent: 0,
+        },
+        wg_conf: wg_conf.clone(),
+        connected_at: utils::unix_now(),
+    });
+    if let Some(path) = listen.strip_prefix(UNIX_PREFIX) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // socket file is left behind if last run is killed
+            if std::path::Path::new(path).exists() {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            // only root can access the socket
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            log::info!("control endpoint is listening on {}", listen);
+            loop {
+                let (stream, _) = listener.accept().await?;
+                spawn_handle(stream, Arc::clone(&ctx));
+            }
+        }
+        #[cfg(windows)]
+        return Err(io::Error::other(format!(
+            "unix socket {} is not supported on windows",
+            path
+        )));
+    }
+    let listener = TcpListener::bind(listen).await?;
+    log::info!("control endpoint is listening on {}", listen);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_handle(stream, Arc::clone(&ctx));
+    }
+}
+
+// handle each connection in its own task, so one client never blocks others
+fn spawn_handle<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S, ctx: Arc<Context>) {
+    tokio::spawn(async move {
+        match tokio::time::timeout(REQUEST_TIMEOUT, handle(stream, &ctx)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => log::warn!("failed to handle control request: {}", err),
+            Err(_) => log::warn!(
+                "control request is dropped after {}s",
+                REQUEST_TIMEOUT.as_secs()
+            ),
+        }
+    });
+}
+
+// remove unix socket file on exit
+pub fn cleanup(listen: &str) {
+    if let Some(path) = listen.strip_prefix(UNIX_PREFIX) {
+        if let Err(err) = std::fs::remove_file(path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                log::warn!("failed to remove {}: {}", path, err);
+            }
+        }
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, ctx: &Context) -> io::Result<()> {
+    let (uapi, wg_conf, connected_at) = (&ctx.uapi, &ctx.wg_conf, ctx.connected_at);
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    // only the request line and headers are needed
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/healthz") => ("200 OK", "text/plain", "ok\n".to_string()),
+        ("GET", "/status") => {
+            let status = json!({
+                "interface": uapi.name,
+                "server_name": wg_conf.server_name,
+                "endpoint": wg_conf.peer_address,
+                "address": wg_conf.address,
+                "address6": wg_conf.address6,
+                "latency": wg_conf.latency,
+                "connected_at": connected_at,
+                "last_handshake_time": uapi.last_handshake_time(),
+            });
+            ("200 OK", "application/json", format!("{}\n", status))
+        }
+        ("GET", "/metrics") => {
+            let mut metrics = String::from("corplink_up 1\n");
+            metrics.push_str(&format!("corplink_connected_at_seconds {}\n", connected_at));
+            if let Some(stats) = uapi.get_stats() {
+                metrics.push_str(&format!(
+                    "corplink_last_handshake_time_seconds {}\n",
+                    stats.last_handshake_time
+                ));
+                metrics.push_str(&format!("corplink_rx_bytes {}\n", stats.rx_bytes));
+                metrics.push_str(&format!("corplink_tx_bytes {}\n", stats.tx_bytes));
+            }
+            if let Some(latency) = wg_conf.latency {
+                metrics.push_str(&format!("corplink_latency_ms {}\n", latency));
+            }
+            ("200 OK", "text/plain; version=0.0.4", metrics)
+        }
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
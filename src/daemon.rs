@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+// set for the background process so it knows it should detach after connected
+const DAEMON_ENV: &str = "CORPLINK_DAEMON";
+const DAEMON_FLAG: &str = "--daemon";
+
+pub fn is_daemon() -> bool {
+    std::env::var_os(DAEMON_ENV).is_some()
+}
+
+fn read_pid(pid_file: &Path) -> Option<i32> {
+    fs::read_to_string(pid_file).ok()?.trim().parse().ok()
+}
+
+fn is_running(pid: i32) -> bool {
+    // signal 0 only checks if the process exists
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+// start a copy of current process in background and wait until it's connected,
+// login prompts still work because stdio is shared before it detaches
+pub fn spawn(pid_file: &Path) -> Result<i32, Box<dyn Error>> {
+    if let Some(pid) = read_pid(pid_file) {
+        if is_running(pid) {
+            return Err(format!("already running with pid {}", pid).into());
+        }
+        log::info!("remove stale pid file {}", pid_file.display());
+        fs::remove_file(pid_file)?;
+    }
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| arg != DAEMON_FLAG)
+        .collect();
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(args)
+        .env(DAEMON_ENV, "1")
+        .spawn()?;
+    let pid = child.id() as i32;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(format!("process {} exits before connected: {}", pid, status).into());
+        }
+        // pid file is written after the tunnel is ready
+        if read_pid(pid_file) == Some(pid) {
+            return Ok(pid);
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+// write pid file and leave the terminal
+pub fn detach(pid_file: &Path) -> Result<(), Box<dyn Error>> {
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    for fd in [0, 1, 2] {
+        unsafe {
+            if libc::dup2(null.as_raw_fd(), fd) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+    }
+    fs::write(pid_file, format!("{}\n", process::id()))?;
+    Ok(())
+}
+
+pub fn remove_pid_file(pid_file: &Path) {
+    // don't remove pid file of another process
+    if read_pid(pid_file) != Some(process::id() as i32) {
+        return;
+    }
+    if let Err(err) = fs::remove_file(pid_file) {
+        log::warn!("failed to remove {}: {}", pid_file.display(), err);
+    }
+}
+
+// send SIGTERM to the daemon and wait for it to exit
+pub fn stop(pid_file: &Path, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let pid = match read_pid(pid_file) {
+        Some(pid) => pid,
+        None => return Err(format!("no valid pid in {}", pid_file.display()).into()),
+    };
+    if !is_running(pid) {
+        fs::remove_file(pid_file)?;
+        return Err(format!("process {} is not running, remove stale pid file", pid).into());
+    }
+    log::info!("send SIGTERM to {}", pid);
+    unsafe {
+        if libc::kill(pid, libc::SIGTERM) < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    let start = Instant::now();
+    while is_running(pid) {
+        if start.elapsed() > timeout {
+            return Err(format!(
+                "process {} is still running after {}s",
+                pid,
+                timeout.as_secs()
+            )
+            .into());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.corplink-rs";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Backend {
+    // set dns for the tunnel interface only
+    Resolvectl,
+    // add dns of the tunnel interface to resolvconf
+    Resolvconf,
+    // rewrite /etc/resolv.conf and restore it from backup
+    File,
+}
+
+// set dns for the tunnel interface with systemd-resolved,
+// or resolvconf and /etc/resolv.conf if systemd-resolved is not available
+pub struct DNSManager {
+    interface: String,
+    backend: Option<Backend>,
+    // only print commands without running them
+    pub dry_run: bool,
+}
+
+fn command_exists(cmd: &str) -> bool {
+    match Command::new(cmd)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Ok(_) => true,
+        Err(err) => err.kind() != ErrorKind::NotFound,
+    }
+}
+
+impl DNSManager {
+    pub fn with_interface(interface: &str) -> DNSManager {
+        DNSManager {
+            interface: interface.to_string(),
+            backend: None,
+            dry_run: false,
+        }
+    }
+
+    fn detect_backend() -> Result<Backend, Error> {
+        if command_exists("resolvectl") {
+            return Ok(Backend::Resolvectl);
+        }
+        if command_exists("resolvconf") {
+            log::info!("resolvectl is not found, use resolvconf instead");
+            return Ok(Backend::Resolvconf);
+        }
+        if Path::new(RESOLV_CONF).exists() {
+            log::warn!(
+                "neither resolvectl nor resolvconf is found, rewrite {} and restore it on exit",
+                RESOLV_CONF
+            );
+            return Ok(Backend::File);
+        }
+        Err(Error::other(format!(
+            "neither resolvectl nor resolvconf is found and {} doesn't exist, dns is not set",
+            RESOLV_CONF
+        )))
+    }
+
+    fn resolvectl(&self, args: &[&str]) -> Result<String, Error> {
+        if self.dry_run {
+            log::info!("[dry-run] resolvectl {}", args.join(" "));
+            return Ok(String::new());
+        }
+        let output = Command::new("resolvectl").args(args).output()?;
+        if !output.status.success() {
+            return Err(Error::other(format!(
+                "resolvectl {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    fn resolvconf(&self, args: &[&str], input: &str) -> Result<(), Error> {
+        if self.dry_run {
+            log::info!("[dry-run] resolvconf {} <<< {:?}", args.join(" "), input);
+            return Ok(());
+        }
+        let mut child = Command::new("resolvconf")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(Error::other(format!(
+                "resolvconf {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn set_dns(&mut self, dns_servers: Vec<&str>, dns_search: Vec<&str>) -> Result<(), Error> {
+        if dns_servers.is_empty() {
+            return Ok(());
+        }
+        let backend = match self.backend {
+            Some(backend) => backend,
+            None => {
+                let backend = Self::detect_backend()?;
+                self.backend = Some(backend);
+                backend
+            }
+        };
+        match backend {
+            Backend::Resolvectl => self.set_dns_with_resolvectl(&dns_servers, &dns_search)?,
+            Backend::Resolvconf => {
+                self.resolvconf(
+                    &["-a", self.interface.as_str()],
+                    &resolv_conf(&dns_servers, &dns_search),
+                )?;
+            }
+            Backend::File => self.set_dns_with_file(&dns_servers, &dns_search)?,
+        }
+        log::debug!(
+            "DNS seted for {} with {}",
+            self.interface,
+            dns_servers.join(",")
+        );
+        Ok(())
+    }
+
+    fn set_dns_with_resolvectl(
+        &self,
+        dns_servers: &[&str],
+        dns_search: &[&str],
+    ) -> Result<(), Error> {
+        let mut args = vec!["dns", self.interface.as_str()];
+        args.extend(dns_servers);
+        self.resolvectl(&args)?;
+
+        // mark domains as routing only to avoid appending them to every bare hostname
+        // without domains, route all queries to the tunnel dns
+        let domains: Vec<String> = if dns_search.is_empty() {
+            vec!["~.".to_string()]
+        } else {
+            dns_search
+                .iter()
+                .map(|domain| format!("~{}", domain.trim_start_matches('~')))
+                .collect()
+        };
+        let mut args = vec!["domain", self.interface.as_str()];
+        args.extend(domains.iter().map(|domain| domain.as_str()));
+        self.resolvectl(&args)?;
+
+        if !self.dry_run {
+            self.verify_dns(dns_servers);
+        }
+        Ok(())
+    }
+
+    // put tunnel dns before the original ones, the original file is kept as backup
+    fn set_dns_with_file(&self, dns_servers: &[&str], dns_search: &[&str]) -> Result<(), Error> {
+        if self.dry_run {
+            log::info!(
+                "[dry-run] backup {} to {} and write:\n{}",
+                RESOLV_CONF,
+                RESOLV_CONF_BACKUP,
+                resolv_conf(dns_servers, dns_search)
+            );
+            return Ok(());
+        }
+        // keep the backup of last run which is not restored
+        if !Path::new(RESOLV_CONF_BACKUP).exists() {
+            fs::copy(RESOLV_CONF, RESOLV_CONF_BACKUP)?;
+        }
+        let original = fs::read_to_string(RESOLV_CONF_BACKUP)?;
+        let mut data = resolv_conf(dns_servers, dns_search);
+        data.push_str(&original);
+        fs::write(RESOLV_CONF, data)
+    }
+
+    // check if dns servers are applied to the interface
+    fn verify_dns(&self, dns_servers: &[&str]) {
+        match self.resolvectl(&["dns", self.interface.as_str()]) {
+            Ok(output) => {
+                for server in dns_servers {
+                    if !output.contains(server) {
+                        log::warn!("DNS {} is not applied to {}", server, self.interface);
+                    }
+                }
+            }
+            Err(err) => {
+                log::warn!("failed to verify DNS of {}: {}", self.interface, err);
+            }
+        }
+    }
+
+    // drop results cached from the dns before connecting
+    pub fn flush_cache(&self) -> Result<(), Error> {
+        if self.backend != Some(Backend::Resolvectl) {
+            log::debug!("no dns cache to flush without systemd-resolved");
+            return Ok(());
+        }
+        self.resolvectl(&["flush-caches"])?;
+        log::debug!("DNS cache flushed");
+        Ok(())
+    }
+
+    pub fn restore_dns(&self) -> Result<(), Error> {
+        match self.backend {
+            Some(Backend::Resolvectl) => {
+                // dns settings are dropped with the interface
+                if !self.dry_run && !Path::new("/sys/class/net").join(&self.interface).exists() {
+                    log::debug!("{} is removed, no need to reset DNS", self.interface);
+                    return Ok(());
+                }
+                self.resolvectl(&["revert", self.interface.as_str()])?;
+            }
+            Some(Backend::Resolvconf) => {
+                self.resolvconf(&["-d", self.interface.as_str()], "")?;
+            }
+            Some(Backend::File) => {
+                if self.dry_run {
+                    log::info!(
+                        "[dry-run] restore {} from {}",
+                        RESOLV_CONF,
+                        RESOLV_CONF_BACKUP
+                    );
+                    return Ok(());
+                }
+                fs::copy(RESOLV_CONF_BACKUP, RESOLV_CONF)?;
+                fs::remove_file(RESOLV_CONF_BACKUP)?;
+            }
+            // dns is not set
+            None => return Ok(()),
+        }
+        log::debug!("DNS reseted for {}", self.interface);
+        Ok(())
+    }
+}
+
+// dns domains can't be routed without systemd-resolved, so all queries go to tunnel dns first
+fn resolv_conf(dns_servers: &[&str], dns_search: &[&str]) -> String {
+    let mut data = String::new();
+    for server in dns_servers {
+        data.push_str(&format!("nameserver {}\n", server));
+    }
+    if !dns_search.is_empty() {
+        data.push_str(&format!("search {}\n", dns_search.join(" ")));
+    }
+    data
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const RESOLVER_DIR: &str = "/etc/resolver";
+// files without it are created by others and never touched
+const RESOLVER_HEADER: &str = "# added by corplink-rs";
+
+pub struct DNSManager {
+    service_dns: HashMap<String, String>,
+    service_dns_search: HashMap<String, String>,
+    // set dns of a service of the tunnel interface with scutil instead of changing all services
+    scutil_interface: Option<String>,
+    // files in /etc/resolver written for split dns
+    resolver_files: Vec<String>,
+    // only send queries of dns domains to vpn dns with scoped resolvers
+    pub split_dns: bool,
+    // only print commands which change dns without running them
+    pub dry_run: bool,
+}
+
+impl DNSManager {
+    pub fn new() -> DNSManager {
+        DNSManager {
+            service_dns: HashMap::new(),
+            service_dns_search: HashMap::new(),
+            scutil_interface: None,
+            resolver_files: Vec::new(),
+            split_dns: false,
+            dry_run: false,
+        }
+    }
+
+    // like vpn clients of apple, dns is added to a service of the tunnel and dropped with it
+    pub fn with_scutil(interface: &str) -> DNSManager {
+        DNSManager {
+            scutil_interface: Some(interface.to_string()),
+            ..DNSManager::new()
+        }
+    }
+
+    fn scutil_key(interface: &str) -> String {
+        format!("State:/Network/Service/corplink-rs-{}/DNS", interface)
+    }
+
+    fn scutil(&self, input: &str) -> Result<String, Error> {
+        if self.dry_run {
+            log::info!("[dry-run] scutil <<< {:?}", input);
+            return Ok(String::new());
+        }
+        let mut child = Command::new("scutil")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(Error::other(format!("scutil failed: {}", output.status)));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    fn networksetup(&self, args: &[&str]) -> Result<(), Error> {
+        if self.dry_run {
+            log::info!("[dry-run] networksetup {}", args.join(" "));
+            return Ok(());
+        }
+        Command::new("networksetup").args(args).status()?;
+        Ok(())
+    }
+
+    fn get_dns_servers(service: &str) -> Result<String, Error> {
+        let dns_output = Command::new("networksetup")
+            .arg("-getdnsservers")
+            .arg(service)
+            .output()?;
+        let dns_response = String::from_utf8_lossy(&dns_output.stdout)
+            .trim()
+            .to_string();
+        // if dns config for this service is not empty, output should be ip addresses seperated in lines without space
+        // otherwise, output should be "There aren't any DNS Servers set on xxx", use "Empty" instead, which can be recongnized in 'networksetup -setdnsservers'
+        let dns_response = if dns_response.contains(" ") {
+            "Empty".to_string()
+        } else {
+            dns_response
+        };
+        Ok(dns_response)
+    }
+
+    fn get_search_domains(service: &str) -> Result<String, Error> {
+        let search_output = Command::new("networksetup")
+            .arg("-getsearchdomains")
+            .arg(service)
+            .output()?;
+        let search_response = String::from_utf8_lossy(&search_output.stdout)
+            .trim()
+            .to_string();
+        let search_response = if search_response.contains(" ") {
+            "Empty".to_string()
+        } else {
+            search_response
+        };
+        Ok(search_response)
+    }
+
+    fn collect_new_service_dns(&mut self) -> Result<(), Error> {
+        let output = Command::new("networksetup")
+            .arg("-listallnetworkservices")
+            .output()?;
+
+        let services = String::from_utf8_lossy(&output.stdout);
+        let lines = services.lines();
+        // Skip the first line's legend
+        for service in lines.skip(1) {
+            // Remove leading '*' and trim whitespace
+            let service = service.trim_start_matches('*').trim();
+            // keep the original dns if set_dns is retried
+            if service.is_empty() || self.service_dns.contains_key(service) {
+                continue;
+            }
+
+            let dns_response = Self::get_dns_servers(service)?;
+            self.service_dns
+                .insert(service.to_string(), dns_response.clone());
+
+            let search_response = Self::get_search_domains(service)?;
+            self.service_dns_search
+                .insert(service.to_string(), search_response.clone());
+
+            log::debug!(
+                "DNS collected for {}, dnsservers: {}, search domain: {}",
+                service,
+                dns_response,
+                search_response
+            )
+        }
+        Ok(())
+    }
+
+    pub fn set_dns(&mut self, dns_servers: Vec<&str>, dns_search: Vec<&str>) -> Result<(), Error> {
+        if dns_servers.is_empty() {
+            return Ok(());
+        }
+        if let Some(interface) = &self.scutil_interface {
+            let mut input = String::from("d.init\n");
+            input.push_str(&format!(
+                "d.add ServerAddresses * {}\n",
+                dns_servers.join(" ")
+            ));
+            // empty match domain sends all queries to the tunnel dns
+            let domains = if dns_search.is_empty() {
+                "\"\"".to_string()
+            } else {
+                dns_search.join(" ")
+            };
+            if !dns_search.is_empty() {
+                input.push_str(&format!("d.add SearchDomains * {}\n", domains));
+            }
+            input.push_str(&format!("d.add SupplementalMatchDomains * {}\n", domains));
+            input.push_str(&format!("d.add InterfaceName {}\n", interface));
+            input.push_str(&format!("set {}\n", Self::scutil_key(interface)));
+            self.scutil(&input)?;
+            log::debug!("DNS seted for {} with {}", interface, dns_servers.join(","));
+            return Ok(());
+        }
+        if self.split_dns && !dns_search.is_empty() {
+            return self.set_resolvers(&dns_servers, &dns_search);
+        }
+        match self.collect_new_service_dns() {
+            Err(e) => return Err(e),
+            _ => {}
+        }
+        for service in self.service_dns.keys() {
+            let mut args = vec!["-setdnsservers", service.as_str()];
+            args.extend(&dns_servers);
+            self.networksetup(&args)?;
+
+            if !dns_search.is_empty() {
+                let mut args = vec!["-setsearchdomains", service.as_str()];
+                args.extend(&dns_search);
+                self.networksetup(&args)?;
+            }
+            log::debug!("DNS seted for {} with {}", service, dns_servers.join(","));
+        }
+
+        Ok(())
+    }
+
+    // a resolver file for each domain, other queries still go to the original dns
+    fn set_resolvers(&mut self, dns_servers: &[&str], dns_search: &[&str]) -> Result<(), Error> {
+        let mut data = format!("{}\n", RESOLVER_HEADER);
+        for server in dns_servers {
+            data.push_str(&format!("nameserver {}\n", server));
+        }
+        if self.dry_run {
+            log::info!(
+                "[dry-run] write {} to {} for {}",
+                data.trim(),
+                RESOLVER_DIR,
+                dns_search.join(",")
+            );
+            return Ok(());
+        }
+        fs::create_dir_all(RESOLVER_DIR)?;
+        for domain in dns_search {
+            let domain = domain.trim_start_matches('~');
+            let file = Path::new(RESOLVER_DIR).join(domain);
+            if let Ok(old) = fs::read_to_string(&file) {
+                if !old.starts_with(RESOLVER_HEADER) {
+                    log::warn!("{} is not created by corplink-rs, skip it", file.display());
+                    continue;
+                }
+            }
+            fs::write(&file, &data)?;
+            self.resolver_files.push(file.to_string_lossy().to_string());
+            log::debug!("DNS seted for {} with {}", domain, dns_servers.join(","));
+        }
+        Ok(())
+    }
+
+    // drop results cached from the dns before connecting
+    pub fn flush_cache(&self) -> Result<(), Error> {
+        for (cmd, args) in [
+            ("dscacheutil", vec!["-flushcache"]),
+            ("killall", vec!["-HUP", "mDNSResponder"]),
+        ] {
+            if self.dry_run {
+                log::info!("[dry-run] {} {}", cmd, args.join(" "));
+                continue;
+            }
+            let status = Command::new(cmd).args(&args).status()?;
+            if !status.success() {
+                return Err(Error::other(format!(
+                    "{} {} failed: {}",
+                    cmd,
+                    args.join(" "),
+                    status
+                )));
+            }
+        }
+        log::debug!("DNS cache flushed");
+        Ok(())
+    }
+
+    pub fn restore_dns(&self) -> Result<(), Error> {
+        if let Some(interface) = &self.scutil_interface {
+            self.scutil(&format!("remove {}\n", Self::scutil_key(interface)))?;
+            log::debug!("DNS reseted for {}", interface);
+            return Ok(());
+        }
+        for file in &self.resolver_files {
+            fs::remove_file(file)?;
+            log::debug!("{} is removed", file);
+        }
+        for (service, dns) in &self.service_dns {
+            let mut args = vec!["-setdnsservers", service.as_str()];
+            args.extend(dns.lines());
+            self.networksetup(&args)?;
+
+            log::debug!("DNS server reseted for {} with {}", service, dns);
+        }
+        for (service, search_domain) in &self.service_dns_search {
+            let mut args = vec!["-setsearchdomains", service.as_str()];
+            args.extend(search_domain.lines());
+            self.networksetup(&args)?;
+            log::debug!(
+                "DNS search domain reseted for {} with {}",
+                service,
+                search_domain
+            )
+        }
+        log::debug!("DNS reseted");
+        Ok(())
+    }
+
+    // compare dns of services with the saved ones, returns services which are not restored
+    pub fn verify_restored(&self) -> Result<Vec<String>, Error> {
+        let mut dirty = Vec::new();
+        if let Some(interface) = &self.scutil_interface {
+            let key = Self::scutil_key(interface);
+            let output = self.scutil(&format!("show {}\n", key))?;
+            if !output.contains("No such key") {
+                dirty.push(key);
+            }
+            return Ok(dirty);
+        }
+        for file in &self.resolver_files {
+            if Path::new(file).exists() {
+                dirty.push(file.clone());
+            }
+        }
+        for (service, dns) in &self.service_dns {
+            if &Self::get_dns_servers(service)? != dns {
+                dirty.push(service.clone());
+                continue;
+            }
+            if let Some(search_domain) = self.service_dns_search.get(service) {
+                if &Self::get_search_domains(service)? != search_domain {
+                    dirty.push(service.clone());
+                }
+            }
+        }
+        Ok(dirty)
+    }
+}
@@ -0,0 +1,44 @@
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(windows)]
+mod win;
+
+#[cfg(target_os = "linux")]
+pub use linux::DNSManager;
+#[cfg(target_os = "macos")]
+pub use macos::DNSManager;
+#[cfg(windows)]
+pub use win::DNSManager;
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+use std::time::Duration;
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// resolve an internal host to check if dns goes through the tunnel
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+pub async fn verify(host: &str) {
+    match tokio::time::timeout(VERIFY_TIMEOUT, tokio::net::lookup_host((host, 0))).await {
+        Ok(Ok(addrs)) => {
+            let addrs: Vec<String> = addrs.map(|addr| addr.ip().to_string()).collect();
+            log::info!(
+                "dns check passed, {} is resolved to {}",
+                host,
+                addrs.join(",")
+            );
+        }
+        Ok(Err(err)) => log::warn!(
+            "failed to resolve {}: {}, dns may not be routed through the tunnel",
+            host,
+            err
+        ),
+        Err(_) => log::warn!(
+            "resolve {} timeout after {}s, dns may not be routed through the tunnel",
+            host,
+            VERIFY_TIMEOUT.as_secs()
+        ),
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::Error;
+use std::process::Command;
+
+// set dns for the tunnel interface only with netsh
+pub struct DNSManager {
+    interface: String,
+    // address families which dns is set for
+    families: Vec<&'static str>,
+    // only print commands without running them
+    pub dry_run: bool,
+}
+
+impl DNSManager {
+    pub fn with_interface(interface: &str) -> DNSManager {
+        DNSManager {
+            interface: interface.to_string(),
+            families: Vec::new(),
+            dry_run: false,
+        }
+    }
+
+    fn run(&self, cmd: &str, args: &[&str]) -> Result<String, Error> {
+        if self.dry_run {
+            log::info!("[dry-run] {} {}", cmd, args.join(" "));
+            return Ok(String::new());
+        }
+        let output = Command::new(cmd).args(args).output()?;
+        if !output.status.success() {
+            // netsh prints errors to stdout
+            return Err(Error::other(format!(
+                "{} {} failed: {}",
+                cmd,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stdout).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    fn netsh(&self, args: &[&str]) -> Result<String, Error> {
+        self.run("netsh", args)
+    }
+
+    pub fn set_dns(&mut self, dns_servers: Vec<&str>, dns_search: Vec<&str>) -> Result<(), Error> {
+        if dns_servers.is_empty() {
+            return Ok(());
+        }
+        let name = format!("name={}", self.interface);
+        // servers of each family in this call, the first one replaces the old ones
+        let mut applied: Vec<&str> = Vec::new();
+        for server in &dns_servers {
+            let family = if server.contains(':') { "ipv6" } else { "ip" };
+            if !self.families.contains(&family) {
+                self.families.push(family);
+            }
+            applied.push(family);
+            let i = applied.iter().filter(|f| **f == family).count();
+            let address = format!("address={}", server);
+            if i == 1 {
+                self.netsh(&[
+                    "interface",
+                    family,
+                    "set",
+                    "dnsservers",
+                    &name,
+                    "source=static",
+                    &address,
+                    "register=none",
+                    "validate=no",
+                ])?;
+            } else {
+                let index = format!("index={}", i);
+                self.netsh(&[
+                    "interface",
+                    family,
+                    "add",
+                    "dnsservers",
+                    &name,
+                    &address,
+                    &index,
+                    "validate=no",
+                ])?;
+            }
+        }
+        if !dns_search.is_empty() {
+            log::info!(
+                "dns domains {} are not supported on windows, all queries go to the tunnel dns first",
+                dns_search.join(",")
+            );
+        }
+        log::debug!(
+            "DNS seted for {} with {}",
+            self.interface,
+            dns_servers.join(",")
+        );
+        Ok(())
+    }
+
+    // drop results cached from the dns before connecting
+    pub fn flush_cache(&self) -> Result<(), Error> {
+        self.run("ipconfig", &["/flushdns"])?;
+        log::debug!("DNS cache flushed");
+        Ok(())
+    }
+
+    pub fn restore_dns(&self) -> Result<(), Error> {
+        let name = format!("name={}", self.interface);
+        // dns settings are dropped with the interface
+        if !self.dry_run
+            && self
+                .netsh(&["interface", "show", "interface", &name])
+                .is_err()
+        {
+            log::debug!("{} is removed, no need to reset DNS", self.interface);
+            return Ok(());
+        }
+        for family in &self.families {
+            self.netsh(&["interface", *family, "delete", "dnsservers", &name, "all"])?;
+        }
+        log::debug!("DNS reseted for {}", self.interface);
+        Ok(())
+    }
+}
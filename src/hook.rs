@@ -0,0 +1,76 @@
+use std::process::Command;
+
+use crate::config::WgConf;
+
+pub const STAGE_POST_UP: &str = "post_up";
+pub const STAGE_PRE_DOWN: &str = "pre_down";
+
+fn env(name: &str, wg_conf: &WgConf) -> Vec<(&'static str, String)> {
+    vec![
+        ("CORPLINK_INTERFACE", name.to_string()),
+        (
+            "CORPLINK_ADDRESS",
+            format!("{}/{}", wg_conf.address, wg_conf.mask),
+        ),
+        ("CORPLINK_ADDRESS6", wg_conf.address6.clone()),
+        ("CORPLINK_ENDPOINT", wg_conf.peer_address.clone()),
+        ("CORPLINK_SERVER", wg_conf.server_name.clone()),
+    ]
+}
+
+fn shell(cmd: &str) -> Command {
+    #[cfg(unix)]
+    {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    }
+    #[cfg(windows)]
+    {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    }
+}
+
+// run hooks one by one with tunnel info in env, return false if any of them fails
+// when strict, the rest are skipped after the first failure
+pub fn run(stage: &str, cmds: &[String], name: &str, wg_conf: &WgConf, strict: bool) -> bool {
+    let mut ok = true;
+    for cmd in cmds {
+        log::info!("run {} hook: {}", stage, cmd);
+        let status = shell(cmd).envs(env(name, wg_conf)).status();
+        match status {
+            Ok(status) if status.success() => continue,
+            Ok(status) => match status.code() {
+                Some(code) => log::warn!("{} hook exits with code {}: {}", stage, code, cmd),
+                None => log::warn!("{} hook is killed by signal: {}", stage, cmd),
+            },
+            Err(err) => log::warn!("failed to run {} hook {}: {}", stage, cmd, err),
+        }
+        ok = false;
+        if strict {
+            break;
+        }
+    }
+    ok
+}
+
+// only print hooks and their env without running them
+pub fn dry_run(stage: &str, cmds: &[String], name: &str, wg_conf: &WgConf) {
+    if cmds.is_empty() {
+        return;
+    }
+    let env: Vec<String> = env(name, wg_conf)
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    for cmd in cmds {
+        log::info!(
+            "[dry-run] run {} hook with {}: {}",
+            stage,
+            env.join(" "),
+            cmd
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::io;
+#[cfg(unix)]
+use std::io::Write;
+#[cfg(unix)]
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::process::{Command, Stdio};
+
+use crate::config::WgConf;
+
+// pf loads rules of com.apple/* anchors by default, so pf.conf is left untouched
+#[cfg(target_os = "macos")]
+const PF_ANCHOR_PREFIX: &str = "com.apple/corplink-rs";
+
+// run cmd with input as stdin and return error with stderr if it fails
+#[cfg(unix)]
+fn run(cmd: &str, args: &[&str], input: Option<&str>, dry_run: bool) -> io::Result<()> {
+    if dry_run {
+        match input {
+            Some(input) => log::info!("[dry-run] {} {} <<< {:?}", cmd, args.join(" "), input),
+            None => log::info!("[dry-run] {} {}", cmd, args.join(" ")),
+        }
+        return Ok(());
+    }
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    }
+    drop(child.stdin.take());
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} {} failed: {}",
+            cmd,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+// only the vpn server is reachable outside the tunnel, so api requests and wg packets still work
+#[cfg(unix)]
+fn server_ip(wg_conf: &WgConf) -> io::Result<IpAddr> {
+    match wg_conf.peer_address.parse::<SocketAddr>() {
+        Ok(addr) => Ok(addr.ip()),
+        Err(err) => Err(io::Error::other(format!(
+            "invalid endpoint {}: {}",
+            wg_conf.peer_address, err
+        ))),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn nft_table(name: &str) -> String {
+    format!("corplink_rs_{}", name.replace('-', "_"))
+}
+
+#[cfg(target_os = "linux")]
+fn iptables_chain(name: &str) -> String {
+    format!("CORPLINK-{}", name)
+}
+
+#[cfg(target_os = "linux")]
+fn has_nft() -> bool {
+    match Command::new("nft")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Ok(_) => true,
+        Err(err) => err.kind() != io::ErrorKind::NotFound,
+    }
+}
+
+// drop all egress except loopback, the tunnel and the vpn server
+#[cfg(unix)]
+pub fn enable(name: &str, wg_conf: &WgConf) -> io::Result<()> {
+    apply(name, wg_conf, false)
+}
+
+// only print commands of enable without running them
+#[cfg(unix)]
+pub fn dry_run(name: &str, wg_conf: &WgConf) -> io::Result<()> {
+    apply(name, wg_conf, true)
+}
+
+#[cfg(target_os = "linux")]
+fn apply(name: &str, wg_conf: &WgConf, dry_run: bool) -> io::Result<()> {
+    let ip = server_ip(wg_conf)?;
+    if has_nft() {
+        let family = if ip.is_ipv4() { "ip" } else { "ip6" };
+        let rules = format!(
+            "table inet {table} {{\n\tchain output {{\n\t\ttype filter hook output priority 0; policy drop;\n\t\toifname \"lo\" accept\n\t\toifname \"{name}\" accept\n\t\t{family} daddr {ip} accept\n\t}}\n}}\n",
+            table = nft_table(name),
+        );
+        return run("nft", &["-f", "-"], Some(&rules), dry_run);
+    }
+    log::info!("nft is not found, use iptables for kill switch");
+    let chain = iptables_chain(name);
+    for cmd in ["iptables", "ip6tables"] {
+        run(cmd, &["-N", &chain], None, dry_run)?;
+        run(
+            cmd,
+            &["-A", &chain, "-o", "lo", "-j", "ACCEPT"],
+            None,
+            dry_run,
+        )?;
+        run(
+            cmd,
+            &["-A", &chain, "-o", name, "-j", "ACCEPT"],
+            None,
+            dry_run,
+        )?;
+        if ip.is_ipv4() == (cmd == "iptables") {
+            run(
+                cmd,
+                &["-A", &chain, "-d", &ip.to_string(), "-j", "ACCEPT"],
+                None,
+                dry_run,
+            )?;
+        }
+        run(cmd, &["-A", &chain, "-j", "DROP"], None, dry_run)?;
+        run(cmd, &["-I", "OUTPUT", "-j", &chain], None, dry_run)?;
+    }
+    Ok(())
+}
+
+// remove rules of last run, missing rules are ignored
+#[cfg(target_os = "linux")]
+pub fn clear(name: &str) -> io::Result<()> {
+    if has_nft() {
+        let table = nft_table(name);
+        if run("nft", &["list", "table", "inet", &table], None, false).is_ok() {
+            run("nft", &["delete", "table", "inet", &table], None, false)?;
+        }
+        // rules may be added by iptables if nft was installed later, so go on
+    }
+    let chain = iptables_chain(name);
+    for cmd in ["iptables", "ip6tables"] {
+        match run(cmd, &["-n", "-L", &chain], None, false) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(_) => continue,
+        }
+        while run(cmd, &["-D", "OUTPUT", "-j", &chain], None, false).is_ok() {}
+        run(cmd, &["-F", &chain], None, false)?;
+        run(cmd, &["-X", &chain], None, false)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn pf_anchor(name: &str) -> String {
+    format!("{}-{}", PF_ANCHOR_PREFIX, name)
+}
+
+#[cfg(target_os = "macos")]
+fn apply(name: &str, wg_conf: &WgConf, dry_run: bool) -> io::Result<()> {
+    let ip = server_ip(wg_conf)?;
+    let rules = format!(
+        "pass out quick on lo0 all\npass out quick on {name} all\npass out quick to {ip}\nblock drop out all\n"
+    );
+    run(
+        "pfctl",
+        &["-a", &pf_anchor(name), "-f", "-"],
+        Some(&rules),
+        dry_run,
+    )?;
+    if dry_run {
+        return run("pfctl", &["-E"], None, dry_run);
+    }
+    // pf is disabled by default, -E takes a reference on it which is released with the token,
+    // so pf is only disabled by clear if nothing else enabled it
+    let output = Command::new("pfctl").arg("-E").output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let token = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("Token : "))
+        .map(|token| token.trim().to_string());
+    match token {
+        Some(token) => {
+            release_pf(name);
+            std::fs::write(pf_token_file(name), format!("{}\n", token))?;
+        }
+        None => log::warn!("no token found in output of pfctl -E: {}", stderr.trim()),
+    }
+    Ok(())
+}
+
+// token of pf reference is kept until reboot, when pf is reset too
+#[cfg(target_os = "macos")]
+fn pf_token_file(name: &str) -> String {
+    format!("/var/run/{}.pftoken", pf_anchor(name).replace('/', "_"))
+}
+
+// release pf reference taken by last run
+#[cfg(target_os = "macos")]
+fn release_pf(name: &str) {
+    let file = pf_token_file(name);
+    let token = match std::fs::read_to_string(&file) {
+        Ok(token) => token,
+        Err(_) => return,
+    };
+    if let Err(err) = run("pfctl", &["-X", token.trim()], None, false) {
+        log::warn!("failed to release pf reference: {}", err);
+    }
+    let _ = std::fs::remove_file(&file);
+}
+
+// remove rules of last run and release pf reference of it
+#[cfg(target_os = "macos")]
+pub fn clear(name: &str) -> io::Result<()> {
+    run("pfctl", &["-a", &pf_anchor(name), "-F", "all"], None, false)?;
+    release_pf(name);
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn enable(_name: &str, _wg_conf: &WgConf) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "kill switch is not supported on windows",
+    ))
+}
+
+#[cfg(windows)]
+pub fn dry_run(name: &str, wg_conf: &WgConf) -> io::Result<()> {
+    enable(name, wg_conf)
+}
+
+#[cfg(windows)]
+pub fn clear(_name: &str) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Login and connect to corplink(feilian) vpn.
+//!
+//! The binary is a thin wrapper around [`Client`], which can also be driven by another process:
+//!
+//! ```no_run
+//! use corplink_rs::{Client, Config, WgConf};
+//!
+//! async fn connect(conf_file: &str) -> Result<WgConf, corplink_rs::client::Error> {
+//!     let mut conf = Config::from_file(conf_file).await;
+//!     // fail instead of blocking on stdin
+//!     conf.non_interactive_override = true;
+//!     let mut c = Client::new(conf)?;
+//!     if c.need_login() {
+//!         c.login().await?;
+//!     }
+//!     c.connect_vpn().await
+//! }
+//! ```
+//!
+//! Without non interactive mode, [`Client::login`] and [`Client::connect_vpn`] prompt on stdin
+//! when email code or 2fa code is needed but missing in config. The returned [`WgConf`] can be applied with
+//! [`wg::start_wg_go`] and [`UAPIClient::config_wg`], and [`Client::disconnect_vpn`] should be
+//! called before the tunnel is stopped.
+
+mod api;
+pub mod audit;
+pub mod client;
+pub mod config;
+pub mod control;
+#[cfg(unix)]
+pub mod daemon;
+pub mod dns;
+pub mod hook;
+pub mod killswitch;
+pub mod lock;
+pub mod logger;
+mod qrcode;
+pub mod resp;
+pub mod state;
+pub mod statsd;
+pub mod status;
+mod template;
+mod tls;
+mod totp;
+pub mod utils;
+pub mod webhook;
+pub mod wg;
+
+pub use client::Client;
+pub use config::{Config, WgConf};
+pub use wg::UAPIClient;
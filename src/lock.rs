@@ -0,0 +1,67 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+// lock of an interface, it's held until the process exits
+// the os releases it on any exit, even if we are killed by a signal
+pub struct InstanceLock {
+    _file: File,
+}
+
+fn locked_error(path: &Path) -> io::Error {
+    let pid = fs::read_to_string(path).unwrap_or_default();
+    let pid = pid.trim();
+    let pid = if pid.is_empty() { "unknown" } else { pid };
+    io::Error::new(
+        io::ErrorKind::WouldBlock,
+        format!("already running (pid {})", pid),
+    )
+}
+
+#[cfg(unix)]
+pub fn acquire(path: &Path) -> io::Result<InstanceLock> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Err(locked_error(path));
+        }
+        return Err(err);
+    }
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(InstanceLock { _file: file })
+}
+
+#[cfg(windows)]
+pub fn acquire(path: &Path) -> io::Result<InstanceLock> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_SHARE_READ: u32 = 1;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    // others can read the pid but can't open it for writing until we exit
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(err) if err.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => {
+            return Err(locked_error(path))
+        }
+        Err(err) => return Err(err),
+    };
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(InstanceLock { _file: file })
+}
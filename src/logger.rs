@@ -0,0 +1,76 @@
+use std::fs;
+use std::sync::{OnceLock, RwLock};
+
+use log::{Log, Metadata, Record};
+
+#[cfg(unix)]
+use crate::config::LOG_TARGET_SYSLOG;
+use crate::config::{LOG_TARGET_FILE, LOG_TARGET_STDOUT};
+
+// logger which can be redirected after config is loaded
+struct Logger {
+    inner: RwLock<Box<dyn Log>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+// log to stdout with env_logger until target is set
+pub fn init() {
+    let logger = env_logger::Builder::from_default_env().build();
+    log::set_max_level(logger.filter());
+    let logger = LOGGER.get_or_init(|| Logger {
+        inner: RwLock::new(Box::new(logger)),
+    });
+    log::set_logger(logger).unwrap();
+}
+
+pub fn set_target(target: &str, file: Option<&str>) -> Result<(), String> {
+    let logger: Box<dyn Log> = match target {
+        LOG_TARGET_STDOUT => return Ok(()),
+        LOG_TARGET_FILE => {
+            let file = match file {
+                Some(file) => file,
+                None => return Err("log_file is required for file log target".to_string()),
+            };
+            let f = match fs::OpenOptions::new().create(true).append(true).open(file) {
+                Ok(f) => f,
+                Err(err) => return Err(format!("failed to open log file {}: {}", file, err)),
+            };
+            Box::new(
+                env_logger::Builder::from_default_env()
+                    .target(env_logger::Target::Pipe(Box::new(f)))
+                    .build(),
+            )
+        }
+        #[cfg(unix)]
+        LOG_TARGET_SYSLOG => {
+            let formatter = syslog::Formatter3164 {
+                facility: syslog::Facility::LOG_DAEMON,
+                hostname: None,
+                process: env!("CARGO_PKG_NAME").to_string(),
+                pid: std::process::id(),
+            };
+            match syslog::unix(formatter) {
+                Ok(logger) => Box::new(syslog::BasicLogger::new(logger)),
+                Err(err) => return Err(format!("failed to connect to syslog: {}", err)),
+            }
+        }
+        _ => return Err(format!("unsupported log target {}", target)),
+    };
+    *LOGGER.get().unwrap().inner.write().unwrap() = logger;
+    Ok(())
+}
@@ -1,57 +1,193 @@
-mod api;
-mod client;
-mod config;
-mod dns;
-mod qrcode;
-mod resp;
-mod state;
-mod template;
-mod totp;
-mod utils;
-mod wg;
-
 #[cfg(windows)]
 use is_elevated;
 
-#[cfg(target_os = "macos")]
-use dns::DNSManager;
+#[cfg(unix)]
+use corplink_rs::daemon;
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+use corplink_rs::dns::DNSManager;
+use corplink_rs::{
+    audit, client, config, control, dns, hook, killswitch, lock, logger, resp, state, statsd,
+    status, utils, webhook, wg,
+};
 
-use env_logger;
-use std::env;
 use std::process::exit;
+use std::time::Duration;
+use std::{fs, io};
 
+use clap::{Parser, Subcommand};
 use client::Client;
 use config::{Config, WgConf};
+use state::State;
+
+const DEFAULT_CONF_FILE: &str = "config.json";
 
-fn print_usage_and_exit(name: &str, conf: &str) {
-    println!("usage:\n\t{} {}", name, conf);
-    exit(1);
+// `corplink-rs config.json` still connects, so config file of connect is a top level positional
+#[derive(Parser)]
+#[command(
+    name = "corplink-rs",
+    about = "corplink client written in rust",
+    disable_version_flag = true,
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<SubCommand>,
+    #[command(flatten)]
+    connect: ConnectArgs,
+    /// Print version and exit
+    #[arg(short = 'V', long)]
+    version: bool,
 }
 
-fn parse_arg() -> String {
-    let mut conf_file = String::from("config.json");
-    let mut args = env::args();
-    // pop name
-    let name = args.next().unwrap();
-    match args.len() {
-        0 => {}
-        1 => {
-            // pop arg
-            let arg = args.next().unwrap();
-            match arg.as_str() {
-                "-h" | "--help" => {
-                    print_usage_and_exit(&name, &conf_file);
-                }
-                _ => {
-                    conf_file = arg;
-                }
-            }
+#[derive(clap::Args)]
+struct ConnectArgs {
+    /// Config file
+    #[arg(default_value = DEFAULT_CONF_FILE)]
+    conf_file: String,
+    /// Override vpn_select_strategy for this run
+    #[arg(long, value_parser = [config::STRATEGY_LATENCY, config::STRATEGY_DEFAULT, config::STRATEGY_FIRST])]
+    strategy: Option<String>,
+    /// Print uapi config and dns commands instead of applying them
+    #[arg(long)]
+    dry_run: bool,
+    /// Select server from the list saved by last run
+    #[arg(long)]
+    use_cached_servers: bool,
+    /// Export wg-quick config to file instead of connecting
+    #[arg(long, value_name = "file")]
+    export: Option<String>,
+    /// Run in background after the tunnel is ready
+    #[arg(long)]
+    daemon: bool,
+    /// Same as the list command
+    #[arg(long)]
+    list: bool,
+    /// Fail instead of reading codes from stdin
+    #[arg(long)]
+    non_interactive: bool,
+}
+
+#[derive(Subcommand)]
+enum SubCommand {
+    /// Print servers with latency and exit
+    List {
+        /// Config file
+        #[arg(default_value = DEFAULT_CONF_FILE)]
+        conf_file: String,
+    },
+    /// Stop the background process started by --daemon
+    Stop {
+        /// Config file
+        #[arg(default_value = DEFAULT_CONF_FILE)]
+        conf_file: String,
+    },
+    /// Remove cookies, login state and saved 2fa seed
+    Logout {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+        /// Config file
+        #[arg(default_value = DEFAULT_CONF_FILE)]
+        conf_file: String,
+    },
+    /// Remove firewall rules kept by kill_switch
+    ClearKillSwitch {
+        /// Config file
+        #[arg(default_value = DEFAULT_CONF_FILE)]
+        conf_file: String,
+    },
+    /// Remove cookies and login state
+    ResetAll {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+        /// Generate new wg keys
+        #[arg(long)]
+        regen_keys: bool,
+        /// Config file
+        #[arg(default_value = DEFAULT_CONF_FILE)]
+        conf_file: String,
+    },
+    /// Check if company name can be resolved to server, no root required
+    CheckCompany {
+        /// Company code name
+        company_name: String,
+    },
+}
+
+enum Command {
+    Connect,
+    List,
+    Stop,
+    Logout,
+    ClearKillSwitch,
+    ResetAll,
+    CheckCompany(String),
+}
+
+struct Args {
+    command: Command,
+    conf_file: String,
+    strategy: Option<String>,
+    yes: bool,
+    regen_keys: bool,
+    dry_run: bool,
+    use_cached_servers: bool,
+    export: Option<String>,
+    daemon: bool,
+    non_interactive: bool,
+}
+
+fn parse_arg() -> Args {
+    let cli = Cli::parse();
+    if cli.version {
+        println!("{}", version());
+        exit(0);
+    }
+    let connect = cli.connect;
+    let mut args = Args {
+        command: Command::Connect,
+        conf_file: connect.conf_file,
+        strategy: connect.strategy,
+        yes: false,
+        regen_keys: false,
+        dry_run: connect.dry_run,
+        use_cached_servers: connect.use_cached_servers,
+        export: connect.export,
+        daemon: connect.daemon,
+        non_interactive: connect.non_interactive,
+    };
+    if connect.list {
+        args.command = Command::List;
+    }
+    match cli.command {
+        None => {}
+        Some(SubCommand::List { conf_file }) => {
+            (args.command, args.conf_file) = (Command::List, conf_file);
         }
-        _ => {
-            print_usage_and_exit(&name, &conf_file);
+        Some(SubCommand::Stop { conf_file }) => {
+            (args.command, args.conf_file) = (Command::Stop, conf_file);
+        }
+        Some(SubCommand::Logout { yes, conf_file }) => {
+            (args.command, args.conf_file) = (Command::Logout, conf_file);
+            args.yes = yes;
+        }
+        Some(SubCommand::ClearKillSwitch { conf_file }) => {
+            (args.command, args.conf_file) = (Command::ClearKillSwitch, conf_file);
+        }
+        Some(SubCommand::ResetAll {
+            yes,
+            regen_keys,
+            conf_file,
+        }) => {
+            (args.command, args.conf_file) = (Command::ResetAll, conf_file);
+            (args.yes, args.regen_keys) = (yes, regen_keys);
+        }
+        Some(SubCommand::CheckCompany { company_name }) => {
+            args.command = Command::CheckCompany(company_name);
         }
     }
-    conf_file
+    args
 }
 
 pub const EPERM: i32 = 1;
@@ -62,20 +198,114 @@ pub const ETIMEDOUT: i32 = 110;
 async fn main() {
     // NOTE: If you want to debug, you should set `RUST_LOG` env to `debug` and run corplink-rs in root
     //  because `check_previlige` will call sudo and drop env if you're not root
-    env_logger::init();
+    logger::init();
 
     print_version();
-    check_previlige();
+    let args = parse_arg();
+    // no need to be root to check company
+    if let Command::CheckCompany(company_name) = &args.command {
+        check_company(company_name).await;
+        exit(0);
+    }
+    // dry run only talks to server, so it works without root
+    if !args.dry_run {
+        check_previlige();
+    }
 
-    let conf_file = parse_arg();
-    let mut conf = Config::from_file(&conf_file).await;
+    let (mut conf, update_conf) = Config::load(&args.conf_file).await;
+    let log_target = conf
+        .log_target
+        .clone()
+        .unwrap_or(config::LOG_TARGET_STDOUT.to_string());
+    if let Err(err) = logger::set_target(&log_target, conf.log_file.as_deref()) {
+        log::error!("failed to set log target to {}: {}", log_target, err);
+        exit(EPERM);
+    }
+    let list = matches!(args.command, Command::List);
+    let pid_file = client::pid_file(&conf);
+    let status_file = client::status_file(&conf);
+    if args.daemon && matches!(args.command, Command::Connect) {
+        // background process takes the lock, the foreground one only waits for it
+        start_daemon(&pid_file);
+    }
+    // another instance would fight over the interface, config and cookies
+    // list and stop never write config, so they work while the tunnel is up
+    // the lock is released by the os on any exit, so it's never removed explicitly
+    let instance_lock = match args.command {
+        Command::List | Command::Stop | Command::CheckCompany(_) => None,
+        _ => {
+            let lock_file = client::lock_file(&conf);
+            match lock::acquire(&lock_file) {
+                Ok(lock) => Some(lock),
+                Err(err) => {
+                    log::error!("failed to lock {}: {}", lock_file.display(), err);
+                    exit(EPERM);
+                }
+            }
+        }
+    };
+    if update_conf && instance_lock.is_some() {
+        conf.save().await;
+    }
+    match args.command {
+        Command::ResetAll => {
+            reset_all(conf, args.regen_keys, args.yes).await;
+            exit(0);
+        }
+        Command::Stop => {
+            stop(&conf);
+            exit(0);
+        }
+        Command::Logout => {
+            logout(conf, args.yes).await;
+            exit(0);
+        }
+        Command::ClearKillSwitch => {
+            clear_kill_switch(&conf.interface_name.unwrap());
+            exit(0);
+        }
+        Command::Connect | Command::List | Command::CheckCompany(_) => {}
+    }
+    // strategy from cli only takes effect for this run and will not be saved
+    conf.vpn_select_strategy_override = args.strategy;
+    conf.use_cached_servers = args.use_cached_servers;
+    conf.non_interactive_override = args.non_interactive;
+    let dry_run = args.dry_run;
+    let export = args.export;
+    let export_dns_hooks = conf.export_dns_hooks.unwrap_or_default();
     let name = conf.interface_name.clone().unwrap();
 
-    #[cfg(target_os = "macos")]
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
     let use_vpn_dns = conf.use_vpn_dns.unwrap_or(false);
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    let dns_apply_delay = conf.dns_apply_delay_ms.unwrap_or_default();
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    let dns_verify_host = conf.dns_verify_host.clone();
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    let flush_dns = conf.flush_dns.unwrap_or_default();
+    #[cfg(target_os = "macos")]
+    let split_dns = conf.split_dns.unwrap_or_default();
+    #[cfg(target_os = "macos")]
+    let macos_dns_backend = conf
+        .macos_dns_backend
+        .clone()
+        .unwrap_or(config::MACOS_DNS_NETWORKSETUP.to_string());
 
     match conf.server {
-        Some(_) => {}
+        Some(_) => {
+            // config from older version, fetch cert settings of company once
+            if conf.server_public_key.is_none() && conf.accept_invalid_certs.is_none() {
+                match client::get_company_url(conf.company_name.as_str()).await {
+                    Ok(resp) => {
+                        apply_company_cert(&mut conf, &resp);
+                        conf.save().await;
+                    }
+                    Err(err) => {
+                        log::warn!("failed to fetch cert settings of company: {}", err);
+                    }
+                }
+            }
+        }
         None => match client::get_company_url(conf.company_name.as_str()).await {
             Ok(resp) => {
                 log::info!(
@@ -84,7 +314,14 @@ async fn main() {
                     resp.en_name,
                     resp.domain
                 );
-                conf.server = Some(resp.domain);
+                match utils::normalize_server_url(&resp.domain) {
+                    Ok(server) => conf.server = Some(server),
+                    Err(err) => {
+                        log::error!("invalid company server {}: {}", resp.domain, err);
+                        exit(EPERM);
+                    }
+                }
+                apply_company_cert(&mut conf, &resp);
                 conf.save().await;
             }
             Err(err) => {
@@ -99,15 +336,130 @@ async fn main() {
     }
 
     let with_wg_log = conf.debug_wg.unwrap_or_default();
+    let verify_cleanup = conf.verify_cleanup.unwrap_or_default();
+    let disable_handshake_watcher = conf.disable_handshake_watcher.unwrap_or_default();
+    let jitter_percent = conf.interval_jitter_percent.unwrap_or_default();
+    let uapi_ready_timeout = Duration::from_secs(
+        conf.uapi_ready_timeout_secs
+            .unwrap_or(config::DEFAULT_UAPI_READY_TIMEOUT_SECS),
+    );
+    let route_metric = conf.route_metric;
+    let control_listen = conf.control_listen.clone();
+    let tunnel_keepalive_target = conf.tunnel_keepalive_target.clone();
+    let statsd_target = conf.statsd_target.clone();
+    let statsd_interval = Duration::from_secs(
+        conf.statsd_interval_secs
+            .unwrap_or(config::DEFAULT_STATSD_INTERVAL_SECS),
+    );
+    let tunnel_keepalive_interval = Duration::from_secs(
+        conf.tunnel_keepalive_interval_secs
+            .unwrap_or(config::DEFAULT_TUNNEL_KEEPALIVE_INTERVAL_SECS),
+    );
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let interface_up_timeout = Duration::from_secs(
+        conf.interface_up_timeout_secs
+            .unwrap_or(config::DEFAULT_INTERFACE_UP_TIMEOUT_SECS),
+    );
+    // 0 disables keep alive
+    let keepalive_interval = conf.keepalive_interval_sec.unwrap();
+    let max_retries = conf.max_retries.unwrap_or(config::DEFAULT_MAX_RETRIES);
+    let shutdown_timeout = Duration::from_secs(
+        conf.shutdown_timeout_secs
+            .unwrap_or(config::DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+    );
+    let handshake_failover_servers = conf.handshake_failover_servers.unwrap_or_default();
+    let reconnect_on_wake = conf.reconnect_on_wake.unwrap_or_default();
+    let kill_switch = conf.kill_switch.unwrap_or_default();
+    let post_up = conf.post_up.clone().unwrap_or_default();
+    let pre_down = conf.pre_down.clone().unwrap_or_default();
+    let hook_strict = conf.hook_strict.unwrap_or_default();
+    let initial_handshake_timeout = conf
+        .initial_handshake_timeout_secs
+        .unwrap_or(config::DEFAULT_INITIAL_HANDSHAKE_TIMEOUT_SECS);
+    #[cfg(target_os = "linux")]
+    if !dry_run && export.is_none() && !list {
+        handle_existing_interface(
+            &name,
+            conf.on_existing_interface
+                .as_deref()
+                .unwrap_or(config::INTERFACE_RECREATE),
+        );
+    }
+    // rules left by a broken tunnel block login, so drop them before any request
+    if kill_switch && !dry_run {
+        clear_kill_switch(&name);
+    }
+    let audit = audit::AuditLog::new(conf.audit_log.clone(), &conf.username);
+    let webhook =
+        webhook::Webhook::new(conf.webhook_url.clone(), conf.webhook_secret.clone(), &name);
     let mut c = Client::new(conf).unwrap();
     let mut logout_retry = true;
     let wg_conf: Option<WgConf>;
 
+    if !c.need_login() {
+        log::info!("check if last session is still valid");
+        match c.check_session().await {
+            Ok(_) => log::info!("last session is valid"),
+            Err(e) => log::warn!("failed to check last session: {}", e),
+        }
+    }
+
+    let mut retry = 0;
     loop {
         if c.need_login() {
             log::info!("not login yet, try to login");
-            c.login().await.unwrap();
+            match c.login().await {
+                Ok(_) => {}
+                Err(e) if e.is_retryable() && retry < max_retries => {
+                    retry += 1;
+                    let delay = utils::backoff(retry);
+                    log::warn!(
+                        "failed to login: {}, retry {}/{} in {:.1}s",
+                        e,
+                        retry,
+                        max_retries,
+                        delay.as_secs_f32()
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                Err(e) => {
+                    audit.record(audit::EVENT_ERROR, "", &format!("failed to login: {}", e));
+                    panic!("{}", e);
+                }
+            }
             log::info!("login success");
+            audit.record(audit::EVENT_LOGIN, "", "login success");
+        }
+        if list {
+            match c.list_vpn_with_latency().await {
+                Ok(servers) => {
+                    print_servers(&servers);
+                    exit(0);
+                }
+                Err(e) if logout_retry && e.to_string().contains("logout") => {
+                    log::warn!("{}", e);
+                    logout_retry = false;
+                    continue;
+                }
+                Err(e) if e.is_retryable() && retry < max_retries => {
+                    retry += 1;
+                    let delay = utils::backoff(retry);
+                    log::warn!(
+                        "failed to list servers: {}, retry {}/{} in {:.1}s",
+                        e,
+                        retry,
+                        max_retries,
+                        delay.as_secs_f32()
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("failed to list servers: {}", e);
+                    exit(EPERM);
+                }
+            }
         }
         log::info!("try to connect");
         match c.connect_vpn().await {
@@ -119,58 +471,347 @@ async fn main() {
                 if logout_retry && e.to_string().contains("logout") {
                     // e contains detail message, so just print it out
                     log::warn!("{}", e);
+                    audit.record(audit::EVENT_RECONNECT, "", &e.to_string());
                     logout_retry = false;
                     continue;
+                } else if e.is_retryable() && retry < max_retries {
+                    retry += 1;
+                    let delay = utils::backoff(retry);
+                    log::warn!(
+                        "failed to connect: {}, retry {}/{} in {:.1}s",
+                        e,
+                        retry,
+                        max_retries,
+                        delay.as_secs_f32()
+                    );
+                    audit.record(audit::EVENT_RECONNECT, "", &e.to_string());
+                    tokio::time::sleep(delay).await;
+                    continue;
                 } else {
+                    audit.record(audit::EVENT_ERROR, "", &e.to_string());
                     panic!("{}", e);
                 }
             }
         };
     }
+    let mut wg_conf = wg_conf.unwrap();
+    if let Some(file) = export {
+        if wg_conf.protocol == 1 {
+            log::warn!(
+                "server {} uses wg over tcp, which is not supported by wg-quick",
+                wg_conf.server_name
+            );
+        }
+        let data = wg::wg_quick_config(&wg_conf, export_dns_hooks);
+        if let Err(err) = write_private_file(&file, &data) {
+            log::error!("failed to export wg config to {}: {}", file, err);
+            exit(EPERM);
+        }
+        log::info!(
+            "wg config of {} is exported to {}",
+            wg_conf.server_name,
+            file
+        );
+        exit(0);
+    }
+    if dry_run {
+        log::info!(
+            "[dry-run] start wg-corplink for {} with {}",
+            name,
+            wg_conf.protocol_name()
+        );
+        wg::dry_run_config(&name, &wg_conf);
+        #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+        if use_vpn_dns {
+            #[cfg(target_os = "macos")]
+            let mut dns_manager = macos_dns_manager(&name, &macos_dns_backend, split_dns);
+            #[cfg(any(target_os = "linux", windows))]
+            let mut dns_manager = DNSManager::with_interface(&name);
+            dns_manager.dry_run = true;
+            let dns_servers = wg_conf.dns.iter().map(|d| d.as_str()).collect();
+            let dns_domains = wg_conf.dns_domains.iter().map(|d| d.as_str()).collect();
+            if let Err(err) = dns_manager.set_dns(dns_servers, dns_domains) {
+                log::warn!("failed to set dns: {}", err);
+            }
+            if flush_dns {
+                if let Err(err) = dns_manager.flush_cache() {
+                    log::warn!("failed to flush dns cache: {}", err);
+                }
+            }
+            if let Err(err) = dns_manager.restore_dns() {
+                log::warn!("failed to delete dns: {}", err);
+            }
+        }
+        hook::dry_run(hook::STAGE_POST_UP, &post_up, &name, &wg_conf);
+        if kill_switch {
+            if let Err(err) = killswitch::dry_run(&name, &wg_conf) {
+                log::warn!("failed to enable kill switch: {}", err);
+            }
+        }
+        hook::dry_run(hook::STAGE_PRE_DOWN, &pre_down, &name, &wg_conf);
+        log::info!("[dry-run] stop wg-corplink for {}", name);
+        // peer info is fetched, so tell server the session is over
+        match tokio::time::timeout(shutdown_timeout, c.disconnect_vpn(&wg_conf)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::warn!("failed to disconnect vpn: {}", e),
+            Err(_) => log::warn!(
+                "disconnect report is skipped because of timeout after {}s",
+                shutdown_timeout.as_secs()
+            ),
+        };
+        exit(0);
+    }
     log::info!("start wg-corplink for {}", &name);
-    let wg_conf = wg_conf.unwrap();
+    write_status(&status_file, status::STATE_CONNECTING, &name, &wg_conf);
     let protocol = wg_conf.protocol;
+    #[cfg(unix)]
+    if let Err(err) = wg::cleanup_stale_socket(&name) {
+        log::error!("{}", err);
+        exit(EPERM);
+    }
     if !wg::start_wg_go(&name, protocol, with_wg_log) {
         log::warn!("failed to start wg-corplink for {}", name);
         exit(EPERM);
     }
-    let mut uapi = wg::UAPIClient { name: name.clone() };
-    match uapi.config_wg(&wg_conf).await {
-        Ok(_) => {}
+    let mut uapi = wg::UAPIClient {
+        name: name.clone(),
+        jitter_percent,
+    };
+    match uapi.wait_ready(uapi_ready_timeout).await {
+        Ok(elapsed) => log::info!("uapi is ready in {:.1}s", elapsed.as_secs_f32()),
+        Err(err) => {
+            log::error!("{}", err);
+            audit.record(audit::EVENT_ERROR, &wg_conf.server_name, &err.to_string());
+            exit(EPERM);
+        }
+    }
+    match uapi
+        .config_wg_with_retry(&wg_conf, uapi_ready_timeout)
+        .await
+    {
+        Ok(_) => {
+            if let Some(metric) = route_metric {
+                apply_route_metric(&name, &wg_conf, metric);
+            }
+            log::info!("{}", wg_conf.summary());
+            audit.record(
+                audit::EVENT_CONNECT,
+                &wg_conf.server_name,
+                &wg_conf.endpoint_detail(),
+            );
+            webhook
+                .send(audit::EVENT_CONNECT, &wg_conf.server_name)
+                .await;
+        }
         Err(err) => {
             log::error!("failed to config interface with uapi for {}: {}", name, err);
+            audit.record(
+                audit::EVENT_ERROR,
+                &wg_conf.server_name,
+                &format!("failed to config interface: {}", err),
+            );
             exit(EPERM);
         }
     }
 
-    #[cfg(target_os = "macos")]
-    let mut dns_manager = DNSManager::new();
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    if !wg::wait_interface_up(&name, interface_up_timeout).await {
+        log::error!(
+            "interface {} is not up in {}s after config, tun device may fail to activate",
+            name,
+            interface_up_timeout.as_secs()
+        );
+        audit.record(
+            audit::EVENT_ERROR,
+            &wg_conf.server_name,
+            "interface is not up",
+        );
+        wg::stop_wg_go();
+        exit(EPERM);
+    }
+
+    // times the peer is switched to another server
+    let mut reconnects = 0;
+    if initial_handshake_timeout > 0 {
+        let timeout = Duration::from_secs(initial_handshake_timeout);
+        let mut handshake = uapi.wait_handshake(timeout).await;
+        if !handshake {
+            log::warn!(
+                "no handshake in {}s, send config to uapi again",
+                initial_handshake_timeout
+            );
+            match uapi.config_wg(&wg_conf).await {
+                Ok(_) => {
+                    if let Some(metric) = route_metric {
+                        apply_route_metric(&name, &wg_conf, metric);
+                    }
+                    handshake = uapi.wait_handshake(timeout).await;
+                    if !handshake {
+                        log::warn!("still no handshake, the connection may not work");
+                    }
+                }
+                Err(err) => {
+                    log::warn!("failed to config interface with uapi for {}: {}", name, err);
+                }
+            }
+        }
+        // server may refuse new sessions, switch the peer to next one
+        while !handshake && reconnects < handshake_failover_servers {
+            reconnects += 1;
+            log::warn!(
+                "no handshake with {}, try next server ({}/{})",
+                wg_conf.server_name,
+                reconnects,
+                handshake_failover_servers
+            );
+            audit.record(
+                audit::EVENT_HANDSHAKE,
+                &wg_conf.server_name,
+                "no first handshake, try next server",
+            );
+            if let Err(e) = c.disconnect_vpn(&wg_conf).await {
+                log::warn!("failed to disconnect {}: {}", wg_conf.server_name, e);
+            }
+            c.exclude_server(&wg_conf.server_name);
+            let next_conf = match c.connect_vpn().await {
+                Ok(conf) => conf,
+                Err(e) => {
+                    log::warn!("no more server to try: {}", e);
+                    break;
+                }
+            };
+            match uapi.config_wg(&next_conf).await {
+                Ok(_) => {
+                    if let Some(metric) = route_metric {
+                        apply_route_metric(&name, &next_conf, metric);
+                    }
+                    log::info!("{}", next_conf.summary());
+                    audit.record(
+                        audit::EVENT_CONNECT,
+                        &next_conf.server_name,
+                        &next_conf.endpoint_detail(),
+                    );
+                    webhook
+                        .send(audit::EVENT_RECONNECT, &next_conf.server_name)
+                        .await;
+                    handshake = uapi.wait_handshake(timeout).await;
+                }
+                Err(err) => {
+                    log::warn!("failed to config interface with uapi for {}: {}", name, err);
+                }
+            }
+            wg_conf = next_conf;
+        }
+        if !handshake {
+            client::diagnose_gateway(&wg_conf.peer_address, wg_conf.protocol).await;
+        }
+        let detail = if handshake {
+            "first handshake is done"
+        } else {
+            "no first handshake"
+        };
+        audit.record(audit::EVENT_HANDSHAKE, &wg_conf.server_name, detail);
+    }
 
     #[cfg(target_os = "macos")]
+    let mut dns_manager = macos_dns_manager(&name, &macos_dns_backend, split_dns);
+    #[cfg(any(target_os = "linux", windows))]
+    let mut dns_manager = DNSManager::with_interface(&name);
+
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
     if use_vpn_dns {
-        match dns_manager.set_dns(vec![&wg_conf.dns], vec![]) {
-            Ok(_) => {}
-            Err(err) => {
-                log::warn!("failed to set dns: {}", err);
+        if dns_apply_delay > 0 {
+            // wait for interface to be fully online
+            tokio::time::sleep(Duration::from_millis(dns_apply_delay)).await;
+        }
+        let dns_servers: Vec<&str> = wg_conf.dns.iter().map(|d| d.as_str()).collect();
+        let dns_domains: Vec<&str> = wg_conf.dns_domains.iter().map(|d| d.as_str()).collect();
+        if let Err(err) = dns_manager.set_dns(dns_servers.clone(), dns_domains.clone()) {
+            log::warn!("failed to set dns: {}, retry", err);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            match dns_manager.set_dns(dns_servers, dns_domains) {
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("failed to set dns: {}", err);
+                }
+            }
+        }
+        if flush_dns {
+            if let Err(err) = dns_manager.flush_cache() {
+                log::warn!("failed to flush dns cache: {}", err);
             }
         }
+        if let Some(host) = &dns_verify_host {
+            dns::verify(host).await;
+        }
+    }
+
+    if !post_up.is_empty()
+        && !hook::run(hook::STAGE_POST_UP, &post_up, &name, &wg_conf, hook_strict)
+    {
+        if hook_strict {
+            log::error!("post_up hook failed, disconnect because hook_strict is set");
+            audit.record(
+                audit::EVENT_ERROR,
+                &wg_conf.server_name,
+                "post_up hook failed",
+            );
+            if let Err(e) = c.disconnect_vpn(&wg_conf).await {
+                log::warn!("failed to disconnect vpn: {}", e);
+            }
+            webhook
+                .send(audit::EVENT_DISCONNECT, &wg_conf.server_name)
+                .await;
+            write_status(&status_file, status::STATE_DISCONNECTED, &name, &wg_conf);
+            wg::stop_wg_go();
+            #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+            if use_vpn_dns {
+                if let Err(err) = dns_manager.restore_dns() {
+                    log::warn!("failed to delete dns: {}", err);
+                }
+            }
+            exit(EPERM);
+        }
+        log::warn!("post_up hook failed, the tunnel is kept");
+    }
+
+    // tunnel is ready, leave the terminal and let the foreground process exit
+    #[cfg(unix)]
+    let daemonized = daemon::is_daemon();
+    #[cfg(unix)]
+    if daemonized {
+        if let Err(err) = daemon::detach(&pid_file) {
+            log::error!("failed to detach from terminal: {}", err);
+            wg::stop_wg_go();
+            #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+            if use_vpn_dns {
+                if let Err(err) = dns_manager.restore_dns() {
+                    log::warn!("failed to delete dns: {}", err);
+                }
+            }
+            exit(EPERM);
+        }
+    }
+
+    write_status(&status_file, status::STATE_CONNECTED, &name, &wg_conf);
+    if kill_switch {
+        match killswitch::enable(&name, &wg_conf) {
+            Ok(_) => log::info!(
+                "kill switch is enabled, only {} and the vpn server are reachable",
+                name
+            ),
+            Err(err) => log::warn!("failed to enable kill switch: {}", err),
+        }
     }
 
     let mut exit_code = 0;
     tokio::select! {
         // handle signal
-        _ = async {
-            match tokio::signal::ctrl_c().await {
-                Ok(_) => {},
-                Err(e) => {
-                    log::warn!("failed to receive signal: {}",e);
-                },
-            }
-            log::info!("ctrl+v received");
-        } => {},
+        _ = wait_signal() => {},
 
         // keep alive
-        _ = c.keep_alive_vpn(&wg_conf, 60) => {
+        _ = c.keep_alive_vpn(&wg_conf, keepalive_interval), if keepalive_interval > 0 => {
+            audit.record(audit::EVENT_ERROR, &wg_conf.server_name, "keep alive failed");
             exit_code = ETIMEDOUT;
         },
 
@@ -178,21 +819,116 @@ async fn main() {
         _ = async {
             uapi.check_wg_connection().await;
             log::warn!("last handshake timeout");
-        } => {
+        }, if !disable_handshake_watcher => {
+            audit.record(audit::EVENT_ERROR, &wg_conf.server_name, "last handshake timeout");
+            exit_code = ETIMEDOUT;
+        },
+
+        // tunnel may die silently during sleep, config it again and check handshake after wake
+        _ = async {
+            let mut uapi = wg::UAPIClient {
+                name: name.clone(),
+                jitter_percent,
+            };
+            let timeout = Duration::from_secs(if initial_handshake_timeout > 0 {
+                initial_handshake_timeout
+            } else {
+                config::DEFAULT_INITIAL_HANDSHAKE_TIMEOUT_SECS
+            });
+            loop {
+                let slept = wg::wait_wake().await;
+                log::info!("system wakes up after about {}s, reconnect {}", slept.as_secs(), name);
+                audit.record(audit::EVENT_RECONNECT, &wg_conf.server_name, "system wakes up");
+                webhook.send(audit::EVENT_RECONNECT, &wg_conf.server_name).await;
+                let since = uapi.last_handshake_time().unwrap_or_default();
+                if let Err(err) = uapi.config_wg(&wg_conf).await {
+                    log::warn!("failed to config interface with uapi for {}: {}", name, err);
+                    break;
+                }
+                if let Some(metric) = route_metric {
+                    apply_route_metric(&name, &wg_conf, metric);
+                }
+                if !uapi.wait_handshake_since(since, timeout).await {
+                    log::warn!("no handshake in {}s after wake", timeout.as_secs());
+                    break;
+                }
+            }
+        }, if reconnect_on_wake => {
+            audit.record(audit::EVENT_ERROR, &wg_conf.server_name, "no handshake after wake");
             exit_code = ETIMEDOUT;
         },
+
+        // keep nat of the tunnel open, tunnel keeps working if it fails
+        _ = async {
+            let target = tunnel_keepalive_target.as_deref().unwrap();
+            if let Err(err) = wg::tunnel_keepalive(target, tunnel_keepalive_interval).await {
+                log::warn!("tunnel keepalive to {} is stopped: {}", target, err);
+            }
+            std::future::pending::<()>().await
+        }, if tunnel_keepalive_target.is_some() => {},
+
+        // push metrics to statsd, tunnel keeps working if it fails
+        _ = async {
+            let target = statsd_target.as_deref().unwrap();
+            if let Err(err) = statsd::push(target, &name, &wg_conf, reconnects, statsd_interval).await {
+                log::warn!("statsd push to {} is stopped: {}", target, err);
+            }
+            std::future::pending::<()>().await
+        }, if statsd_target.is_some() => {},
+
+        // refresh status file, tunnel keeps working if it fails
+        _ = async {
+            if let Err(err) = status::update(&status_file, &name, &wg_conf, wg::WG_CHECK_INTERVAL).await {
+                log::warn!("failed to update {}: {}", status_file.display(), err);
+            }
+            std::future::pending::<()>().await
+        } => {},
+
+        // serve control endpoint, tunnel keeps working if it fails
+        _ = async {
+            let listen = control_listen.as_deref().unwrap();
+            if let Err(err) = control::serve(listen, &name, &wg_conf).await {
+                log::warn!("control endpoint on {} is stopped: {}", listen, err);
+            }
+            std::future::pending::<()>().await
+        }, if control_listen.is_some() => {},
     }
 
     // shutdown
+    if !pre_down.is_empty() {
+        hook::run(hook::STAGE_PRE_DOWN, &pre_down, &name, &wg_conf, false);
+    }
     log::info!("disconnecting vpn...");
-    match c.disconnect_vpn(&wg_conf).await {
-        Ok(_) => {}
-        Err(e) => log::warn!("failed to disconnect vpn: {}", e),
+    match tokio::time::timeout(shutdown_timeout, c.disconnect_vpn(&wg_conf)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log::warn!("failed to disconnect vpn: {}", e),
+        Err(_) => log::warn!(
+            "disconnect report is skipped because of timeout after {}s",
+            shutdown_timeout.as_secs()
+        ),
     };
 
+    // read the final stats before wg-go is stopped
+    write_status(&status_file, status::STATE_DISCONNECTED, &name, &wg_conf);
     wg::stop_wg_go();
+    if let Some(listen) = &control_listen {
+        control::cleanup(listen);
+    }
+    if kill_switch {
+        if exit_code == 0 {
+            clear_kill_switch(&name);
+        } else {
+            log::warn!(
+                "kill switch is kept because tunnel is broken, run corplink-rs again or clear-kill-switch to restore network"
+            );
+        }
+    }
+    audit.record(audit::EVENT_DISCONNECT, &wg_conf.server_name, "");
+    webhook
+        .send(audit::EVENT_DISCONNECT, &wg_conf.server_name)
+        .await;
 
-    #[cfg(target_os = "macos")]
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
     if use_vpn_dns {
         match dns_manager.restore_dns() {
             Ok(_) => {}
@@ -202,10 +938,353 @@ async fn main() {
         }
     }
 
+    if verify_cleanup {
+        check_cleanup(&name);
+        #[cfg(target_os = "macos")]
+        if use_vpn_dns {
+            match dns_manager.verify_restored() {
+                Ok(services) => {
+                    for service in services {
+                        log::warn!("dns of {} is not restored", service);
+                    }
+                }
+                Err(err) => {
+                    log::warn!("failed to verify dns: {}", err);
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    if daemonized {
+        daemon::remove_pid_file(&pid_file);
+    }
+
     log::info!("reach exit");
     exit(exit_code)
 }
 
+// wait for ctrl+c, or SIGTERM sent by `stop` on unix
+async fn wait_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                log::warn!("failed to listen SIGTERM: {}", e);
+                return wait_ctrl_c().await;
+            }
+        };
+        tokio::select! {
+            _ = wait_ctrl_c() => {},
+            _ = terminate.recv() => log::info!("SIGTERM received"),
+        }
+    }
+    #[cfg(windows)]
+    wait_ctrl_c().await
+}
+
+async fn wait_ctrl_c() {
+    match tokio::signal::ctrl_c().await {
+        Ok(_) => {}
+        Err(e) => {
+            log::warn!("failed to receive signal: {}", e);
+        }
+    }
+    log::info!("ctrl+v received");
+}
+
+// print servers as a table, name can be used as vpn_server_name
+fn print_servers(servers: &[(resp::RespVpnInfo, i64)]) {
+    let header = ["name", "ip", "port", "protocol", "latency"];
+    println!(
+        "{:<24} {:<40} {:<6} {:<8} {}",
+        header[0], header[1], header[2], header[3], header[4]
+    );
+    for (vpn, latency) in servers {
+        let protocol = match vpn.protocol_mode {
+            1 => "tcp".to_string(),
+            2 => "udp".to_string(),
+            mode => format!("unknown({})", mode),
+        };
+        let latency = match latency {
+            -1 => "timeout".to_string(),
+            latency => format!("{}ms", latency),
+        };
+        println!(
+            "{:<24} {:<40} {:<6} {:<8} {}",
+            vpn.en_name, vpn.ip, vpn.vpn_port, protocol, latency
+        );
+    }
+}
+
+fn clear_kill_switch(name: &str) {
+    match killswitch::clear(name) {
+        Ok(_) => log::info!("kill switch of {} is cleared", name),
+        Err(err) => log::warn!("failed to clear kill switch of {}: {}", name, err),
+    }
+}
+
+fn write_status(path: &std::path::Path, state: &str, name: &str, wg_conf: &WgConf) {
+    if let Err(err) = status::write(path, state, name, wg_conf) {
+        log::warn!("failed to write status to {}: {}", path.display(), err);
+    }
+}
+
+fn start_daemon(pid_file: &std::path::Path) {
+    #[cfg(unix)]
+    {
+        if daemon::is_daemon() {
+            return;
+        }
+        match daemon::spawn(pid_file) {
+            Ok(pid) => {
+                log::info!(
+                    "connected and running in background with pid {}, pid file is {}",
+                    pid,
+                    pid_file.display()
+                );
+                exit(0);
+            }
+            Err(err) => {
+                log::error!("failed to start daemon: {}", err);
+                exit(EPERM);
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        log::error!(
+            "--daemon is not supported on windows, {} is not written",
+            pid_file.display()
+        );
+        exit(EPERM);
+    }
+}
+
+fn stop(conf: &Config) {
+    let pid_file = client::pid_file(conf);
+    #[cfg(unix)]
+    {
+        // wait a bit longer than the daemon waits for disconnect report
+        let timeout = Duration::from_secs(
+            conf.shutdown_timeout_secs
+                .unwrap_or(config::DEFAULT_SHUTDOWN_TIMEOUT_SECS)
+                + 5,
+        );
+        match daemon::stop(&pid_file, timeout) {
+            Ok(_) => log::info!("corplink-rs is stopped"),
+            Err(err) => {
+                log::error!("failed to stop corplink-rs: {}", err);
+                exit(EPERM);
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        log::error!(
+            "stop is not supported on windows, {} is not read",
+            pid_file.display()
+        );
+        exit(EPERM);
+    }
+}
+
+// pin company public key if it's enabled, and trust the self signed cert of company
+// invalid certs are accepted only when company uses self signed cert without providing it
+fn apply_company_cert(conf: &mut Config, resp: &resp::RespCompany) {
+    if resp.enable_public_key && !resp.public_key.is_empty() {
+        log::info!("server cert will be checked with company public key");
+        conf.server_public_key = Some(resp.public_key.clone());
+    }
+    if resp.enable_self_signed && !resp.self_signed_cert.is_empty() {
+        log::info!("self signed cert of company is trusted");
+        conf.self_signed_cert = Some(resp.self_signed_cert.clone());
+        conf.accept_invalid_certs = Some(false);
+    } else {
+        conf.accept_invalid_certs = Some(resp.enable_self_signed);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn handle_existing_interface(name: &str, action: &str) {
+    if !wg::interface_exists(name) {
+        return;
+    }
+    match action {
+        config::INTERFACE_REUSE => {
+            log::info!("interface {} already exists, reuse it", name);
+        }
+        config::INTERFACE_RECREATE => {
+            log::info!("interface {} already exists, delete it before start", name);
+            if let Err(err) = wg::delete_interface(name) {
+                log::error!("failed to delete interface {}: {}", name, err);
+                exit(EPERM);
+            }
+        }
+        config::INTERFACE_FAIL => {
+            log::error!("interface {} already exists", name);
+            exit(EPERM);
+        }
+        _ => {
+            log::error!("unsupported on_existing_interface {}", action);
+            exit(EPERM);
+        }
+    }
+}
+
+// write file which is only readable by owner because it contains private key
+fn write_private_file(file: &str, data: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(file)?;
+    io::Write::write_all(&mut f, data.as_bytes())
+}
+
+fn apply_route_metric(name: &str, wg_conf: &WgConf, metric: u32) {
+    #[cfg(target_os = "linux")]
+    match wg::set_route_metric(name, &wg_conf.routes, metric) {
+        Ok(_) => log::info!("routes of {} are set with metric {}", name, metric),
+        Err(err) => log::warn!("failed to set route metric of {}: {}", name, err),
+    }
+    #[cfg(not(target_os = "linux"))]
+    log::warn!(
+        "route metric {} is not supported on this platform, {} routes of {} are not changed",
+        metric,
+        wg_conf.routes.len(),
+        name
+    );
+}
+
+// remove cookies and login state, so next run will start from scratch
+async fn confirm(msg: &str, yes: bool) -> bool {
+    if yes {
+        return true;
+    }
+    println!("{}, continue? [y/N]", msg);
+    let input = utils::read_line().await;
+    matches!(input.trim(), "y" | "Y" | "yes")
+}
+
+fn remove_cookie_file(conf: &Config) {
+    let cookie_file = client::cookie_file(conf);
+    match fs::remove_file(&cookie_file) {
+        Ok(_) => log::info!("cookie file {} is removed", cookie_file.display()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => {
+            log::error!("failed to remove {}: {}", cookie_file.display(), err);
+            exit(EPERM);
+        }
+    }
+}
+
+async fn reset_all(mut conf: Config, regen_keys: bool, yes: bool) {
+    let msg = format!(
+        "login state of {} and cookie file {} will be removed",
+        conf.conf_file.as_ref().unwrap(),
+        client::cookie_file(&conf).display()
+    );
+    if !confirm(&msg, yes).await {
+        log::info!("reset is canceled");
+        return;
+    }
+    remove_cookie_file(&conf);
+    conf.state = Some(State::Init);
+    conf.email_code_requested_at = None;
+    conf.last_server = None;
+    if regen_keys {
+        let (public_key, private_key) = utils::gen_wg_keypair();
+        (conf.public_key, conf.private_key) = (Some(public_key), Some(private_key));
+        log::info!("new wg keypair is generated");
+    }
+    conf.save().await;
+    log::info!("reset done");
+}
+
+// recover from a stuck session, 2fa seed is dropped too and fetched again on next login
+async fn logout(mut conf: Config, yes: bool) {
+    let msg = format!(
+        "login state and 2fa seed of {} and cookie file {} will be removed",
+        conf.conf_file.as_ref().unwrap(),
+        client::cookie_file(&conf).display()
+    );
+    if !confirm(&msg, yes).await {
+        log::info!("logout is canceled");
+        return;
+    }
+    remove_cookie_file(&conf);
+    conf.state = Some(State::Init);
+    conf.email_code_requested_at = None;
+    conf.code = None;
+    conf.code_owner = None;
+    conf.save().await;
+    log::info!("logout done");
+}
+
+async fn check_company(company_name: &str) {
+    match client::check_company(company_name).await {
+        Ok((resp, cert)) => {
+            log::info!(
+                "company name is {}(zh)/{}(en) server is {}",
+                resp.zh_name,
+                resp.en_name,
+                resp.domain
+            );
+            if let Some(cert) = cert {
+                log::info!("cert sha256 fingerprint of match api is {}", cert);
+            }
+        }
+        Err(err) => {
+            log::error!("failed to check company {}: {}", company_name, err);
+            exit(EPERM);
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn macos_dns_manager(name: &str, backend: &str, split_dns: bool) -> DNSManager {
+    let mut dns_manager = match backend {
+        // scutil always routes queries by domains
+        config::MACOS_DNS_SCUTIL => DNSManager::with_scutil(name),
+        config::MACOS_DNS_NETWORKSETUP => DNSManager::new(),
+        _ => {
+            log::error!("unsupported macos_dns_backend {}", backend);
+            exit(EPERM);
+        }
+    };
+    dns_manager.split_dns = split_dns;
+    dns_manager
+}
+
+// check if interface and routes are cleaned after wg stopped
+fn check_cleanup(name: &str) {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    if wg::interface_exists(name) {
+        log::warn!("interface {} still exists after exit", name);
+        match wg::interface_routes(name) {
+            Ok(routes) => {
+                for route in routes {
+                    log::warn!("route {} of {} is left behind", route, name);
+                }
+            }
+            Err(err) => {
+                log::warn!("failed to list routes of {}: {}", name, err);
+            }
+        }
+    }
+    #[cfg(windows)]
+    log::info!(
+        "cleanup verification of {} is not supported on windows",
+        name
+    );
+}
+
 fn check_previlige() {
     #[cfg(unix)]
     match sudo::escalate_if_needed() {
@@ -223,8 +1302,12 @@ fn check_previlige() {
     }
 }
 
-fn print_version() {
+fn version() -> String {
     let pkg_name = env!("CARGO_PKG_NAME");
     let pkg_version = env!("CARGO_PKG_VERSION");
-    log::info!("running {}@{}", pkg_name, pkg_version);
+    format!("{}@{}", pkg_name, pkg_version)
+}
+
+fn print_version() {
+    log::info!("running {}", version());
 }
@@ -0,0 +1,60 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::config::WgConf;
+use crate::utils;
+use crate::wg::UAPIClient;
+
+const PREFIX: &str = "corplink";
+
+// push gauges to statsd over udp periodically, same metrics as /metrics of control endpoint
+pub async fn push(
+    target: &str,
+    name: &str,
+    wg_conf: &WgConf,
+    reconnects: u32,
+    interval: Duration,
+) -> io::Result<()> {
+    let uapi = UAPIClient {
+        name: name.to_string(),
+        jitter_percent: 0,
+    };
+    let addr = match tokio::net::lookup_host(target).await?.next() {
+        Some(addr) => addr,
+        None => return Err(io::Error::other(format!("no address found for {}", target))),
+    };
+    let bind_addr = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let sock = UdpSocket::bind(bind_addr).await?;
+    sock.connect(addr).await?;
+    log::info!(
+        "push metrics to statsd {} every {}s",
+        target,
+        interval.as_secs()
+    );
+    loop {
+        let mut metrics = vec![format!("{}.up:1|g", PREFIX)];
+        metrics.push(format!("{}.reconnects:{}|g", PREFIX, reconnects));
+        if let Some(stats) = uapi.get_stats() {
+            if stats.last_handshake_time > 0 {
+                let age = utils::unix_now() as i64 - stats.last_handshake_time;
+                metrics.push(format!("{}.handshake_age_seconds:{}|g", PREFIX, age));
+            }
+            metrics.push(format!("{}.rx_bytes:{}|g", PREFIX, stats.rx_bytes));
+            metrics.push(format!("{}.tx_bytes:{}|g", PREFIX, stats.tx_bytes));
+        }
+        if let Some(latency) = wg_conf.latency {
+            metrics.push(format!("{}.latency_ms:{}|g", PREFIX, latency));
+        }
+        // statsd server may be restarted, so keep pushing
+        if let Err(err) = sock.send(metrics.join("\n").as_bytes()).await {
+            log::warn!("failed to push metrics to statsd {}: {}", target, err);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,75 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::config::WgConf;
+use crate::utils;
+use crate::wg::{Stats, UAPIClient};
+
+pub const STATE_CONNECTING: &str = "connecting";
+pub const STATE_CONNECTED: &str = "connected";
+pub const STATE_DISCONNECTED: &str = "disconnected";
+
+#[derive(Serialize)]
+struct Status<'a> {
+    state: &'a str,
+    interface: &'a str,
+    server_name: &'a str,
+    endpoint: &'a str,
+    address: &'a str,
+    address6: &'a str,
+    // latency in ms of the server when it's selected, null if not measured
+    latency: Option<i64>,
+    last_handshake_time: i64,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    updated_at: u64,
+}
+
+// write status of the connection, stats are read from uapi after wg-go is started
+pub fn write(path: &Path, state: &str, name: &str, wg_conf: &WgConf) -> io::Result<()> {
+    let stats = if state == STATE_CONNECTING {
+        Stats::default()
+    } else {
+        let uapi = UAPIClient {
+            name: name.to_string(),
+            jitter_percent: 0,
+        };
+        uapi.get_stats().unwrap_or_default()
+    };
+    let endpoint = if stats.endpoint.is_empty() {
+        &wg_conf.peer_address
+    } else {
+        &stats.endpoint
+    };
+    let status = Status {
+        state,
+        interface: name,
+        server_name: &wg_conf.server_name,
+        endpoint,
+        address: &wg_conf.address,
+        address6: &wg_conf.address6,
+        latency: wg_conf.latency,
+        last_handshake_time: stats.last_handshake_time,
+        rx_bytes: stats.rx_bytes,
+        tx_bytes: stats.tx_bytes,
+        updated_at: utils::unix_now(),
+    };
+    let data = serde_json::to_string_pretty(&status)?;
+    utils::write_atomic(path, format!("{}\n", data).as_bytes())
+}
+
+// refresh the status of live connection periodically
+pub async fn update(
+    path: &Path,
+    name: &str,
+    wg_conf: &WgConf,
+    interval: Duration,
+) -> io::Result<()> {
+    loop {
+        tokio::time::sleep(interval).await;
+        write(path, STATE_CONNECTED, name, wg_conf)?;
+    }
+}
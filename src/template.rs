@@ -21,9 +21,9 @@ impl Template {
         }
     }
 
-    /// ```
     /// # Examples
     ///
+    /// ```ignore
     /// let template = Template::new("Hi, my name is {{name}} and I'm a {{lang}} developer.");
     ///
     /// let mut args = HashMap::new();
@@ -0,0 +1,282 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{
+    Certificate, CertificateError, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xa0;
+
+// one der element and the data after it
+struct Der<'a> {
+    tag: u8,
+    element: &'a [u8],
+    value: &'a [u8],
+    rest: &'a [u8],
+}
+
+fn read_der(data: &[u8]) -> Option<Der<'_>> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let mut len = 0;
+        for b in data.get(2..2 + n)? {
+            len = (len << 8) | *b as usize;
+        }
+        (len, 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    let element = data.get(..end)?;
+    Some(Der {
+        tag,
+        element,
+        value: &element[header..],
+        rest: &data[end..],
+    })
+}
+
+// issuer name and subject public key info of a cert, as they are needed by trust anchors and pinning
+// returns value of issuer and the whole spki element
+fn parse_cert(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    let cert = read_der(cert)?;
+    if cert.tag != TAG_SEQUENCE {
+        return None;
+    }
+    let tbs = read_der(cert.value)?;
+    if tbs.tag != TAG_SEQUENCE {
+        return None;
+    }
+    let mut rest = tbs.value;
+    if rest.first() == Some(&TAG_VERSION) {
+        rest = read_der(rest)?.rest;
+    }
+    // serial number and signature algorithm
+    rest = read_der(rest)?.rest;
+    rest = read_der(rest)?.rest;
+    let issuer = read_der(rest)?;
+    // validity and subject
+    rest = read_der(issuer.rest)?.rest;
+    rest = read_der(rest)?.rest;
+    let spki = read_der(rest)?;
+    if spki.tag != TAG_SEQUENCE {
+        return None;
+    }
+    Some((issuer.value, spki.element))
+}
+
+// accept the server cert if it carries the company key, or it's issued by the company key
+// signature of the handshake is still verified with the cert key, so the server must own the key
+struct PinnedKeyVerifier {
+    // der of subject public key info
+    key: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let (issuer, spki) = parse_cert(&end_entity.0).ok_or(rustls::Error::InvalidCertificate(
+            CertificateError::BadEncoding,
+        ))?;
+        if spki == self.key.as_slice() {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let key = match read_der(&self.key) {
+            Some(key) if key.tag == TAG_SEQUENCE => key.value,
+            _ => return Err(rustls::Error::General("invalid company key".to_string())),
+        };
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(std::iter::once(
+            OwnedTrustAnchor::from_subject_spki_name_constraints(issuer, key, None::<&[u8]>),
+        ));
+        let verifier = WebPkiVerifier::new(roots, None);
+        match verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ) {
+            // vpn apis are requested by ip, so only the issuer is checked
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::UnknownIssuer | CertificateError::BadSignature,
+            )) => Err(rustls::Error::General(
+                "server cert doesn't match the company public key".to_string(),
+            )),
+            result => result,
+        }
+    }
+}
+
+// tls config which rejects servers not matching the company key during handshake,
+// so nothing is sent to a server with a forged cert
+pub fn pinned_config(key: &[u8]) -> Result<ClientConfig, String> {
+    if !matches!(read_der(key), Some(der) if der.tag == TAG_SEQUENCE && der.rest.is_empty()) {
+        return Err("not a der encoded public key".to_string());
+    }
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedKeyVerifier { key: key.to_vec() }))
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rcgen::{BasicConstraints, Certificate as Cert, CertificateParams, IsCa};
+
+    fn new_ca() -> Cert {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Cert::from_params(params).unwrap()
+    }
+
+    fn new_leaf() -> Cert {
+        Cert::from_params(CertificateParams::new(vec!["vpn.example.com".to_string()])).unwrap()
+    }
+
+    fn verify(
+        key: &[u8],
+        cert: Vec<u8>,
+        server_name: &str,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verifier = PinnedKeyVerifier { key: key.to_vec() };
+        verifier.verify_server_cert(
+            &Certificate(cert),
+            &[],
+            &ServerName::try_from(server_name).unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+    }
+
+    #[test]
+    fn read_der_lengths() {
+        let der = read_der(&[0x02, 0x01, 0x05, 0xff]).unwrap();
+        assert_eq!(
+            (der.tag, der.value, der.rest),
+            (0x02, &[0x05][..], &[0xff][..])
+        );
+
+        // long form length
+        let mut data = vec![0x04, 0x81, 0x80];
+        data.extend([0u8; 0x80]);
+        let der = read_der(&data).unwrap();
+        assert_eq!((der.value.len(), der.rest.len()), (0x80, 0));
+        let mut data = vec![0x04, 0x82, 0x01, 0x00];
+        data.extend([0u8; 0x100]);
+        assert_eq!(read_der(&data).unwrap().value.len(), 0x100);
+
+        // truncated header, value and length bytes
+        assert!(read_der(&[]).is_none());
+        assert!(read_der(&[0x30]).is_none());
+        assert!(read_der(&[0x30, 0x03, 0x00]).is_none());
+        assert!(read_der(&[0x04, 0x82, 0x01]).is_none());
+        assert!(read_der(&data[..data.len() - 1]).is_none());
+        // indefinite and oversized lengths
+        assert!(read_der(&[0x30, 0x80, 0x00, 0x00]).is_none());
+        assert!(read_der(&[0x04, 0x85, 0xff, 0xff, 0xff, 0xff, 0xff]).is_none());
+        assert!(read_der(&[0x04, 0x84, 0xff, 0xff, 0xff, 0xff]).is_none());
+    }
+
+    #[test]
+    fn parse_cert_issuer_and_spki() {
+        let ca = new_ca();
+        let leaf = new_leaf();
+        let cert = leaf.serialize_der_with_signer(&ca).unwrap();
+        let ca_cert = ca.serialize_der().unwrap();
+
+        let (issuer, spki) = parse_cert(&cert).unwrap();
+        assert_eq!(spki, leaf.get_key_pair().public_key_der().as_slice());
+        // issuer of the leaf is the subject of ca, which is the same as the issuer of self signed ca
+        let (ca_issuer, ca_spki) = parse_cert(&ca_cert).unwrap();
+        assert_eq!(issuer, ca_issuer);
+        assert_eq!(ca_spki, ca.get_key_pair().public_key_der().as_slice());
+
+        for len in [0, 1, 10, cert.len() / 2, cert.len() - 1] {
+            assert!(parse_cert(&cert[..len]).is_none(), "truncated to {}", len);
+        }
+        assert!(parse_cert(spki).is_none());
+    }
+
+    #[test]
+    fn accept_cert_with_pinned_key() {
+        let leaf = new_leaf();
+        let key = leaf.get_key_pair().public_key_der();
+        assert!(verify(&key, leaf.serialize_der().unwrap(), "1.2.3.4").is_ok());
+    }
+
+    #[test]
+    fn accept_cert_issued_by_pinned_key() {
+        let ca = new_ca();
+        let key = ca.get_key_pair().public_key_der();
+        let cert = new_leaf().serialize_der_with_signer(&ca).unwrap();
+        assert!(verify(&key, cert.clone(), "vpn.example.com").is_ok());
+        // gateways are requested by ip, which is not in the cert
+        assert!(verify(&key, cert, "1.2.3.4").is_ok());
+    }
+
+    #[test]
+    fn reject_cert_of_other_key() {
+        let ca = new_ca();
+        let other = new_ca();
+        let key = other.get_key_pair().public_key_der();
+        for cert in [
+            new_leaf().serialize_der_with_signer(&ca).unwrap(),
+            new_leaf().serialize_der().unwrap(),
+        ] {
+            match verify(&key, cert, "vpn.example.com") {
+                Err(rustls::Error::General(msg)) => {
+                    assert_eq!(msg, "server cert doesn't match the company public key")
+                }
+                result => panic!("unexpected result {:?}", result.map(|_| ())),
+            }
+        }
+        // ca of another company isn't a server cert either
+        assert!(verify(&key, ca.serialize_der().unwrap(), "vpn.example.com").is_err());
+    }
+
+    #[test]
+    fn reject_broken_cert() {
+        let leaf = new_leaf();
+        let key = leaf.get_key_pair().public_key_der();
+        let cert = leaf.serialize_der().unwrap();
+        assert!(matches!(
+            verify(&key, cert[..cert.len() / 2].to_vec(), "1.2.3.4"),
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::BadEncoding
+            ))
+        ));
+    }
+
+    #[test]
+    fn pinned_config_checks_key() {
+        let key = new_ca().get_key_pair().public_key_der();
+        assert!(pinned_config(&key).is_ok());
+        assert!(pinned_config(&key[..key.len() - 1]).is_err());
+        let mut trailing = key.clone();
+        trailing.push(0);
+        assert!(pinned_config(&trailing).is_err());
+        assert!(pinned_config(b"not der").is_err());
+    }
+}
@@ -1,16 +1,110 @@
 use std::error::Error;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
 use base32::Alphabet;
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD as base64;
 use rand::rngs::OsRng;
+use rand::Rng;
 use x25519_dalek::{PublicKey, StaticSecret};
 
 pub async fn read_line() -> String {
     io::stdin().lock().lines().next().unwrap().unwrap()
 }
 
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// write to a temp file and rename it, so readers never see a partial file
+// and the old file is kept if we are killed or the system crashes during writing
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, data, false)
+}
+
+// same as write_atomic, but the file is only readable by owner because it contains secrets
+pub fn write_private_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, data, true)
+}
+
+fn write_atomic_with(path: &Path, data: &[u8], private: bool) -> io::Result<()> {
+    // unique name and create_new, so a file or symlink planted by others is never reused
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{:08x}.tmp",
+        std::process::id(),
+        OsRng.gen::<u32>()
+    ));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(&tmp)?;
+    let result = write_synced(&mut f, path, data, private);
+    // file is closed before rename, which fails on windows if it's still open
+    drop(f);
+    let result = result.and_then(|_| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_synced(f: &mut std::fs::File, path: &Path, data: &[u8], private: bool) -> io::Result<()> {
+    if !private {
+        // keep permissions of the original file
+        if let Ok(metadata) = std::fs::metadata(path) {
+            f.set_permissions(metadata.permissions())?;
+        }
+    }
+    f.write_all(data)?;
+    f.sync_all()
+}
+
+// files with secrets should only be readable by owner, fix it if group or others can access it
+#[cfg(unix)]
+pub fn check_private_file(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.permissions().mode() & 0o777,
+        Err(_) => return,
+    };
+    if mode & 0o077 == 0 {
+        return;
+    }
+    log::warn!(
+        "{} contains secrets but is accessible by group or others (mode {:o}), change it to 600",
+        path.display(),
+        mode
+    );
+    if let Err(err) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)) {
+        log::warn!("failed to change mode of {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(windows)]
+pub fn check_private_file(_path: &Path) {}
+
+// hide secrets in logs except the last `keep` chars, full value is only shown at trace level
+pub fn redact(s: &str, keep: usize) -> String {
+    if log::log_enabled!(log::Level::Trace) {
+        return s.to_string();
+    }
+    let len = s.chars().count();
+    let keep = keep.min(len);
+    let tail: String = s.chars().skip(len - keep).collect();
+    format!("{}{}", "*".repeat(len - keep), tail)
+}
+
 pub fn b32_decode(s: &str) -> Vec<u8> {
     base32::decode(Alphabet::RFC4648 { padding: true }, s).unwrap()
 }
@@ -25,7 +119,9 @@ pub fn gen_wg_keypair() -> (String, String) {
 pub fn gen_public_key_from_private(private_key: &String) -> Result<String, Box<dyn Error>> {
     match base64.decode(private_key) {
         Ok(key) => {
-            let key: [u8; 32] = key.try_into().unwrap();
+            let key: [u8; 32] = key
+                .try_into()
+                .map_err(|key: Vec<u8>| format!("private key has {} bytes, not 32", key.len()))?;
             let sk = StaticSecret::from(key);
             let public_key = PublicKey::from(&sk);
             Ok(base64.encode(public_key.to_bytes()))
@@ -34,6 +130,16 @@ pub fn gen_public_key_from_private(private_key: &String) -> Result<String, Box<d
     }
 }
 
+// decode pem or bare base64 to der, header and footer lines are ignored
+pub fn pem_to_der(pem: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let data: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .map(|line| line.trim())
+        .collect();
+    Ok(base64.decode(data)?)
+}
+
 pub fn b64_decode_to_hex(s: &str) -> String {
     let data = base64.decode(s).unwrap();
     let mut hex = String::new();
@@ -42,3 +148,211 @@ pub fn b64_decode_to_hex(s: &str) -> String {
     }
     hex
 }
+
+// join host and port as socket address, ipv6 literal will be wrapped in brackets
+pub fn join_host_port(host: &str, port: u16) -> String {
+    match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]:{}", host, port),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+// normalize server to https://host[:port] without path
+pub fn normalize_server_url(server: &str) -> Result<String, Box<dyn Error>> {
+    let server = server.trim();
+    // scheme is case insensitive
+    let server = match server.split_once("://") {
+        Some((scheme, host))
+            if scheme.eq_ignore_ascii_case("https") || scheme.eq_ignore_ascii_case("http") =>
+        {
+            format!("https://{host}")
+        }
+        Some((scheme, _)) => return Err(format!("unsupported scheme {scheme}").into()),
+        None => format!("https://{server}"),
+    };
+    let url = reqwest::Url::parse(&server)?;
+    if url.host_str().is_none() {
+        return Err(format!("no host in {server}").into());
+    }
+    Ok(url.origin().ascii_serialization())
+}
+
+pub fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// check if domain is the suffix or a sub domain of suffix
+pub fn domain_matches(domain: &str, suffix: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let suffix = suffix.trim_end_matches('.').to_lowercase();
+    domain == suffix || domain.ends_with(&format!(".{}", suffix))
+}
+
+pub fn ip_in_cidr(ip: &str, cidr: &str) -> Result<bool, Box<dyn Error>> {
+    let (net, prefix) = match cidr.split_once('/') {
+        Some((net, prefix)) => (net, Some(prefix.parse::<u32>()?)),
+        None => (cidr, None),
+    };
+    let ip: IpAddr = ip.parse()?;
+    let net: IpAddr = net.parse()?;
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let prefix = prefix.unwrap_or(32);
+            if prefix > 32 {
+                return Err(format!("invalid prefix length in {}", cidr).into());
+            }
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            Ok(u32::from(ip) & mask == u32::from(net) & mask)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let prefix = prefix.unwrap_or(128);
+            if prefix > 128 {
+                return Err(format!("invalid prefix length in {}", cidr).into());
+            }
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            Ok(u128::from(ip) & mask == u128::from(net) & mask)
+        }
+        // different address family
+        _ => Ok(false),
+    }
+}
+
+// randomly stretch or shrink the duration by up to percent
+pub fn jitter(d: Duration, percent: u32) -> Duration {
+    if percent == 0 {
+        return d;
+    }
+    let percent = percent.min(100) as f64 / 100.0;
+    let factor = rand::thread_rng().gen_range(-percent..=percent);
+    d.mul_f64(1.0 + factor)
+}
+
+// exponential backoff from 1s with factor 2 and cap 30s, with 20% jitter
+pub fn backoff(retry: u32) -> Duration {
+    let secs = 1u64 << retry.saturating_sub(1).min(5);
+    jitter(Duration::from_secs(secs.min(30)), 20)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_host_port_v4_and_v6() {
+        assert_eq!(join_host_port("1.2.3.4", 51820), "1.2.3.4:51820");
+        assert_eq!(join_host_port("::1", 51820), "[::1]:51820");
+        // both are valid endpoints for wg
+        for endpoint in [
+            join_host_port("1.2.3.4", 51820),
+            join_host_port("::1", 51820),
+        ] {
+            assert!(endpoint.parse::<std::net::SocketAddr>().is_ok());
+        }
+    }
+
+    #[test]
+    fn normalize_server_url_cases() {
+        for (server, normalized) in [
+            ("https://vpn.example.com", "https://vpn.example.com"),
+            ("http://vpn.example.com", "https://vpn.example.com"),
+            ("vpn.example.com", "https://vpn.example.com"),
+            (" https://vpn.example.com/ ", "https://vpn.example.com"),
+            (
+                "https://vpn.example.com/api/login?a=b",
+                "https://vpn.example.com",
+            ),
+            (
+                "https://vpn.example.com:8443/path",
+                "https://vpn.example.com:8443",
+            ),
+            ("vpn.example.com:8443", "https://vpn.example.com:8443"),
+            ("https://vpn.example.com:443", "https://vpn.example.com"),
+            ("HTTPS://VPN.example.com", "https://vpn.example.com"),
+            ("Http://vpn.example.com", "https://vpn.example.com"),
+            ("https://1.2.3.4:8443", "https://1.2.3.4:8443"),
+        ] {
+            assert_eq!(
+                normalize_server_url(server).unwrap(),
+                normalized,
+                "{}",
+                server
+            );
+        }
+        for server in ["ftp://vpn.example.com", "https://", "https://:8443"] {
+            assert!(normalize_server_url(server).is_err(), "{}", server);
+        }
+    }
+
+    #[test]
+    fn ip_in_cidr_cases() {
+        for (ip, cidr, matched) in [
+            ("10.1.2.3", "10.0.0.0/8", true),
+            ("11.1.2.3", "10.0.0.0/8", false),
+            ("10.1.2.3", "10.1.2.3/32", true),
+            ("10.1.2.4", "10.1.2.3/32", false),
+            ("10.1.2.3", "10.1.2.3", true),
+            ("10.1.2.3", "10.1.2.128/25", false),
+            ("1.2.3.4", "0.0.0.0/0", true),
+            ("fd00::1", "fd00::/8", true),
+            ("fe80::1", "fd00::/8", false),
+            ("fd00::1", "fd00::1/128", true),
+            ("fd00::1", "::/0", true),
+            // different address family never matches
+            ("10.1.2.3", "::/0", false),
+            ("fd00::1", "0.0.0.0/0", false),
+            ("::ffff:10.1.2.3", "10.0.0.0/8", false),
+        ] {
+            assert_eq!(ip_in_cidr(ip, cidr).unwrap(), matched, "{} in {}", ip, cidr);
+        }
+        for (ip, cidr) in [
+            ("10.1.2.3", "10.0.0.0/33"),
+            ("fd00::1", "fd00::/129"),
+            ("10.1.2.3", "10.0.0.0/x"),
+            ("10.1.2.3", "vpn.example.com/8"),
+            ("10.1.2", "10.0.0.0/8"),
+        ] {
+            assert!(ip_in_cidr(ip, cidr).is_err(), "{} in {}", ip, cidr);
+        }
+    }
+
+    #[test]
+    fn domain_matches_cases() {
+        for (domain, suffix, matched) in [
+            ("example.com", "example.com", true),
+            ("git.example.com", "example.com", true),
+            ("a.b.example.com", "example.com", true),
+            ("Git.Example.COM", "example.com", true),
+            ("git.example.com.", "example.com", true),
+            ("git.example.com", "example.com.", true),
+            // look alike suffix without a dot boundary
+            ("badexample.com", "example.com", false),
+            ("example.com.evil.net", "example.com", false),
+            ("example.com", "git.example.com", false),
+            ("com", "example.com", false),
+        ] {
+            assert_eq!(
+                domain_matches(domain, suffix),
+                matched,
+                "{} of {}",
+                domain,
+                suffix
+            );
+        }
+    }
+
+    #[test]
+    fn public_key_from_private_of_wrong_length() {
+        let (_, private_key) = gen_wg_keypair();
+        assert!(gen_public_key_from_private(&private_key).is_ok());
+        assert!(gen_public_key_from_private(&base64.encode([1u8; 16])).is_err());
+        assert!(gen_public_key_from_private(&"not base64".to_string()).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::ClientBuilder;
+use serde::Serialize;
+use sha2::Sha256;
+
+pub const SIGNATURE_HEADER: &str = "X-Corplink-Signature";
+// webhook should not hold up connecting or shutdown
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct WebhookEvent<'a> {
+    state: &'a str,
+    server: &'a str,
+    interface: &'a str,
+    time: String,
+}
+
+// post connection state changes to webhook_url, failures are only logged
+#[derive(Clone)]
+pub struct Webhook {
+    url: Option<String>,
+    secret: Option<String>,
+    interface: String,
+}
+
+impl Webhook {
+    pub fn new(url: Option<String>, secret: Option<String>, interface: &str) -> Webhook {
+        Webhook {
+            url,
+            secret,
+            interface: interface.to_string(),
+        }
+    }
+
+    pub async fn send(&self, state: &str, server: &str) {
+        let url = match &self.url {
+            Some(url) => url,
+            None => return,
+        };
+        let e = WebhookEvent {
+            state,
+            server,
+            interface: &self.interface,
+            time: Utc::now().to_rfc3339(),
+        };
+        let body = serde_json::to_string(&e).unwrap();
+        if let Err(err) = self.post(url, body).await {
+            log::warn!("failed to send {} to webhook {}: {}", state, url, err);
+        }
+    }
+
+    async fn post(&self, url: &str, body: String) -> Result<(), reqwest::Error> {
+        let c = ClientBuilder::new().timeout(TIMEOUT).build()?;
+        let mut req = c
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            req = req.header(SIGNATURE_HEADER, sign(secret, &body));
+        }
+        req.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+// hex of hmac-sha256 of the body, so the receiver can verify it with the shared secret
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    let sum = mac.finalize().into_bytes();
+    let hex: String = sum.iter().map(|c| format!("{c:02x}")).collect();
+    format!("sha256={}", hex)
+}
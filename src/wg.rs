@@ -1,9 +1,27 @@
 use std::ffi::{c_char, c_void, CStr, CString};
 use std::io;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+#[cfg(unix)]
+use std::process::Command;
 use std::time;
 
 use crate::{config, utils};
 
+// default refresh key timeout of wg is 2 min
+// we set wg connection timeout to 5 min
+pub const WG_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(5 * 60);
+const TUNNEL_KEEPALIVE_PORT: u16 = 9;
+const WAKE_POLL_INTERVAL: time::Duration = time::Duration::from_secs(5);
+// clock gap smaller than this is treated as scheduling delay instead of sleep
+const WAKE_THRESHOLD: time::Duration = time::Duration::from_secs(30);
+#[cfg(unix)]
+const UAPI_SOCKET_DIR: &str = "/var/run/wireguard";
+#[cfg(target_os = "macos")]
+const UTUN_BASE: u32 = 12345;
+#[cfg(target_os = "macos")]
+const UTUN_MAX: u32 = 32767;
+
 #[allow(clippy::all)]
 #[allow(
     dead_code,
@@ -39,6 +57,31 @@ fn uapi(buff: &[u8]) -> Vec<u8> {
     }
 }
 
+// remove uapi socket left by a killed process, so wg-go can listen on it again
+#[cfg(unix)]
+pub fn cleanup_stale_socket(name: &str) -> io::Result<()> {
+    let path = std::path::PathBuf::from(UAPI_SOCKET_DIR).join(format!("{}.sock", name));
+    if !path.exists() {
+        return Ok(());
+    }
+    match std::os::unix::net::UnixStream::connect(&path) {
+        Ok(_) => Err(io::Error::other(format!(
+            "{} is in use, another wg of {} may be running",
+            path.display(),
+            name
+        ))),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            log::info!("remove stale uapi socket {}", path.display());
+            std::fs::remove_file(&path)
+        }
+        Err(err) => Err(io::Error::other(format!(
+            "failed to check {}: {}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
 pub fn stop_wg_go() {
     stop_wg();
 }
@@ -53,69 +96,587 @@ pub fn start_wg_go(name: &str, protocol: i32, with_log: bool) -> bool {
     matches!(ret, 0)
 }
 
+#[cfg(target_os = "linux")]
+pub fn interface_exists(name: &str) -> bool {
+    Path::new("/sys/class/net").join(name).exists()
+}
+
+#[cfg(target_os = "macos")]
+pub fn interface_exists(name: &str) -> bool {
+    match Command::new("ifconfig").arg(name).output() {
+        Ok(output) => output.status.success(),
+        Err(_) => false,
+    }
+}
+
+// macos only accepts utun[0-9]+ and the number is an int16
+#[cfg(target_os = "macos")]
+pub fn is_valid_interface_name(name: &str) -> bool {
+    match name.strip_prefix("utun") {
+        Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+            n.parse::<u32>().is_ok_and(|n| n <= UTUN_MAX)
+        }
+        _ => false,
+    }
+}
+
+// find an unused utun name, starting from a large number to avoid the system ones
+#[cfg(target_os = "macos")]
+pub fn free_interface_name() -> Option<String> {
+    let output = Command::new("ifconfig").arg("-l").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    let used: Vec<&str> = output.split_whitespace().collect();
+    (UTUN_BASE..=UTUN_MAX)
+        .map(|n| format!("utun{}", n))
+        .find(|name| !used.contains(&name.as_str()))
+}
+
+#[cfg(target_os = "linux")]
+pub fn interface_is_up(name: &str) -> bool {
+    // IFF_UP is the lowest bit of flags
+    match std::fs::read_to_string(Path::new("/sys/class/net").join(name).join("flags")) {
+        Ok(flags) => match i64::from_str_radix(flags.trim().trim_start_matches("0x"), 16) {
+            Ok(flags) => flags & 0x1 != 0,
+            Err(_) => false,
+        },
+        Err(_) => false,
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn interface_is_up(name: &str) -> bool {
+    match Command::new("ifconfig").arg(name).output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).contains("<UP"),
+        Err(_) => false,
+    }
+}
+
+// poll until the interface is up, returns false if timeout
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub async fn wait_interface_up(name: &str, timeout: time::Duration) -> bool {
+    let start = time::Instant::now();
+    loop {
+        if interface_is_up(name) {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+    }
+}
+
+// send a tiny udp packet to the target in tunnel periodically, so nat keeps the path open
+// target without port is probed on the discard port
+pub async fn tunnel_keepalive(target: &str, interval: time::Duration) -> io::Result<()> {
+    let target = match target.parse::<std::net::SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => std::net::SocketAddr::new(
+            target
+                .parse()
+                .map_err(|e| io::Error::other(format!("invalid target {}: {}", target, e)))?,
+            TUNNEL_KEEPALIVE_PORT,
+        ),
+    };
+    let bind_addr = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let sock = tokio::net::UdpSocket::bind(bind_addr).await?;
+    log::info!(
+        "send tunnel keepalive to {} every {}s",
+        target,
+        interval.as_secs()
+    );
+    loop {
+        // the packet is dropped by target, error is logged only because route may be changing
+        if let Err(err) = sock.send_to(&[0], target).await {
+            log::warn!("failed to send tunnel keepalive to {}: {}", target, err);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// monotonic clock stops while the system sleeps but wall clock doesn't,
+// a large gap between them means the system just woke up, returns how long it slept
+pub async fn wait_wake() -> time::Duration {
+    loop {
+        let instant = time::Instant::now();
+        let wall = time::SystemTime::now();
+        tokio::time::sleep(WAKE_POLL_INTERVAL).await;
+        let monotonic = instant.elapsed();
+        if let Ok(wall) = wall.elapsed() {
+            if wall > monotonic + WAKE_THRESHOLD {
+                return wall - monotonic;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn interface_routes(name: &str) -> io::Result<Vec<String>> {
+    let mut routes = Vec::new();
+    for family in ["-4", "-6"] {
+        let output = Command::new("ip")
+            .args([family, "route", "show", "dev", name])
+            .output()?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            routes.push(line.trim().to_string());
+        }
+    }
+    Ok(routes)
+}
+
+// remove routes of the interface which are not wanted, returns wanted routes to be added
+// routes of interface address added by kernel are kept
+#[cfg(target_os = "linux")]
+pub fn reconcile_routes(name: &str, routes: &[String]) -> io::Result<Vec<String>> {
+    let wanted: Vec<String> = routes.iter().map(|route| normalize_route(route)).collect();
+    let mut existing = Vec::new();
+    for (family, default) in [("-4", "0.0.0.0/0"), ("-6", "::/0")] {
+        let output = Command::new("ip")
+            .args([family, "route", "show", "dev", name])
+            .output()?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if line.contains("proto kernel") {
+                continue;
+            }
+            let route = match line.split_whitespace().next() {
+                Some("default") => default.to_string(),
+                Some(route) => normalize_route(route),
+                None => continue,
+            };
+            if wanted.contains(&route) {
+                existing.push(route);
+                continue;
+            }
+            log::info!("remove stale route {} of {}", route, name);
+            let output = Command::new("ip")
+                .args([family, "route", "del", &route, "dev", name])
+                .output()?;
+            if !output.status.success() {
+                log::warn!(
+                    "failed to remove route {}: {}",
+                    route,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+        }
+    }
+    if !existing.is_empty() {
+        log::info!("{} route(s) of {} already exist", existing.len(), name);
+    }
+    Ok(wanted
+        .into_iter()
+        .filter(|route| !existing.contains(route))
+        .collect())
+}
+
+// list routes which go through the interface
+#[cfg(target_os = "macos")]
+pub fn interface_routes(name: &str) -> io::Result<Vec<String>> {
+    let output = Command::new("netstat").arg("-rn").output()?;
+    let routes = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.split_whitespace().any(|col| col == name))
+        .map(|line| {
+            line.split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect();
+    Ok(routes)
+}
+
+#[cfg(target_os = "linux")]
+pub fn delete_interface(name: &str) -> io::Result<()> {
+    let status = Command::new("ip").args(["link", "delete", name]).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "ip link delete {} exits with {}",
+            name, status
+        )));
+    }
+    Ok(())
+}
+
+// add prefix length to bare address
+fn normalize_route(route: &str) -> String {
+    if route.contains('/') {
+        route.to_string()
+    } else if route.contains(':') {
+        format!("{route}/128")
+    } else {
+        format!("{route}/32")
+    }
+}
+
+// replace routes added by wg-corplink with ones with the metric
+#[cfg(target_os = "linux")]
+pub fn set_route_metric(name: &str, routes: &[String], metric: u32) -> io::Result<()> {
+    for route in routes {
+        let route = normalize_route(route);
+        let family = if route.contains(':') { "-6" } else { "-4" };
+        // route may not exist yet, so ignore the result of deleting
+        Command::new("ip")
+            .args([family, "route", "del", &route, "dev", name])
+            .output()?;
+        let output = Command::new("ip")
+            .args([family, "route", "add", &route, "dev", name, "metric"])
+            .arg(metric.to_string())
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "failed to add route {} with metric {}: {}",
+                route,
+                metric,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+    }
+    Ok(())
+}
+
+// build uapi config steps from wg conf, only routes in the list are added
+// each step is sent alone because uapi only returns one errno for a set operation
+pub fn config_steps(conf: &config::WgConf, routes: &[String]) -> Vec<(String, String)> {
+    let mut steps = Vec::new();
+    let mut buff = String::from("set=1\n");
+    // standard wg-go uapi operations
+    // see https://www.wireguard.com/xplatform/#configuration-protocol
+    let private_key = utils::b64_decode_to_hex(&conf.private_key);
+    let public_key = utils::b64_decode_to_hex(&conf.peer_key);
+    buff.push_str(format!("private_key={private_key}\n").as_str());
+    buff.push_str("replace_peers=true\n".to_string().as_str());
+    buff.push_str(format!("public_key={public_key}\n").as_str());
+    buff.push_str("replace_allowed_ips=true\n".to_string().as_str());
+    buff.push_str(format!("endpoint={}\n", conf.peer_address).as_str());
+    buff.push_str(
+        format!(
+            "persistent_keepalive_interval={}\n",
+            conf.persistent_keepalive
+        )
+        .as_str(),
+    );
+    for allowed_ip in &conf.allowed_ips {
+        if allowed_ip.contains("/") {
+            buff.push_str(format!("allowed_ip={allowed_ip}\n").as_str());
+        } else {
+            buff.push_str(format!("allowed_ip={allowed_ip}/32\n").as_str());
+        }
+    }
+    buff.push('\n');
+    steps.push(("peer".to_string(), buff));
+
+    // wg-corplink uapi operations
+    let mut buff = String::from("set=1\n");
+    let addr = format!("{}/{}", conf.address, conf.mask);
+    let mtu = conf.mtu;
+    buff.push_str(format!("address={addr}\n").as_str());
+    buff.push_str(format!("mtu={mtu}\n").as_str());
+    buff.push_str("up=true\n".to_string().as_str());
+    buff.push('\n');
+    steps.push(("interface".to_string(), buff));
+    // v6 address is assigned alone, so v6 routes have a source address on the interface
+    if !conf.address6.is_empty() {
+        let addr6 = normalize_route(&conf.address6);
+        steps.push((
+            format!("address {addr6}"),
+            format!("set=1\naddress={addr6}\n\n"),
+        ));
+    }
+    for route in routes {
+        let route = normalize_route(route);
+        steps.push((
+            format!("route {route}"),
+            format!("set=1\nroute={route}\n\n"),
+        ));
+    }
+    // end operation
+    steps
+}
+
+// wg-quick config of wg conf, dns of the tunnel is applied with PostUp/PreDown like corplink-rs does
+pub fn wg_quick_config(conf: &config::WgConf, dns_hooks: bool) -> String {
+    let mut address = format!("{}/{}", conf.address, conf.mask);
+    if !conf.address6.is_empty() {
+        address = format!("{}, {}", address, conf.address6);
+    }
+    let mut buff = String::from("[Interface]\n");
+    buff.push_str(format!("PrivateKey = {}\n", conf.private_key).as_str());
+    buff.push_str(format!("Address = {}\n", address).as_str());
+    buff.push_str(format!("MTU = {}\n", conf.mtu).as_str());
+    if dns_hooks && !conf.dns.is_empty() {
+        #[cfg(target_os = "linux")]
+        {
+            let domains = if conf.dns_domains.is_empty() {
+                "~.".to_string()
+            } else {
+                conf.dns_domains
+                    .iter()
+                    .map(|domain| format!("~{}", domain.trim_start_matches('~')))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            };
+            buff.push_str(
+                format!(
+                    "PostUp = resolvectl dns %i {}; resolvectl domain %i {}\n",
+                    conf.dns.join(" "),
+                    domains
+                )
+                .as_str(),
+            );
+            buff.push_str("PreDown = resolvectl revert %i\n");
+        }
+        // wg-quick sets dns of all network services with networksetup and restores them on down
+        #[cfg(target_os = "macos")]
+        {
+            let mut dns = conf.dns.clone();
+            dns.extend(conf.dns_domains.iter().cloned());
+            buff.push_str(format!("DNS = {}\n", dns.join(", ")).as_str());
+        }
+        // dns is dropped with the interface, so there is no PreDown
+        #[cfg(windows)]
+        {
+            let mut cmds = Vec::new();
+            for (i, dns) in conf.dns.iter().enumerate() {
+                if i == 0 {
+                    cmds.push(format!(
+                        "netsh interface ipv4 set dnsservers name=\"%i\" static {} primary",
+                        dns
+                    ));
+                } else {
+                    cmds.push(format!(
+                        "netsh interface ipv4 add dnsservers name=\"%i\" {} index={}",
+                        dns,
+                        i + 1
+                    ));
+                }
+            }
+            buff.push_str(format!("PostUp = {}\n", cmds.join(" & ")).as_str());
+        }
+    }
+    buff.push_str("\n[Peer]\n");
+    buff.push_str(format!("PublicKey = {}\n", conf.peer_key).as_str());
+    buff.push_str(format!("Endpoint = {}\n", conf.peer_address).as_str());
+    let allowed_ips: Vec<String> = conf
+        .allowed_ips
+        .iter()
+        .map(|allowed_ip| normalize_route(allowed_ip))
+        .collect();
+    buff.push_str(format!("AllowedIPs = {}\n", allowed_ips.join(", ")).as_str());
+    buff.push_str(format!("PersistentKeepalive = {}\n", conf.persistent_keepalive).as_str());
+    buff
+}
+
+// print what will be sent to uapi instead of sending it, private key is hidden
+pub fn dry_run_config(name: &str, conf: &config::WgConf) {
+    for (step, buff) in config_steps(conf, &conf.routes) {
+        let buff: Vec<&str> = buff
+            .lines()
+            .map(|line| {
+                if line.starts_with("private_key=") {
+                    "private_key=(hidden)"
+                } else {
+                    line
+                }
+            })
+            .collect();
+        log::info!(
+            "[dry-run] uapi config {} for {}:\n{}",
+            step,
+            name,
+            buff.join("\n")
+        );
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    // 0 if no handshake yet
+    pub last_handshake_time: i64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub endpoint: String,
+}
+
 pub struct UAPIClient {
     pub name: String,
+    pub jitter_percent: u32,
 }
 
 impl UAPIClient {
     pub async fn config_wg(&mut self, conf: &config::WgConf) -> io::Result<()> {
-        let mut buff = String::from("set=1\n");
-        // standard wg-go uapi operations
-        // see https://www.wireguard.com/xplatform/#configuration-protocol
-        let private_key = utils::b64_decode_to_hex(&conf.private_key);
-        let public_key = utils::b64_decode_to_hex(&conf.peer_key);
-        buff.push_str(format!("private_key={private_key}\n").as_str());
-        buff.push_str("replace_peers=true\n".to_string().as_str());
-        buff.push_str(format!("public_key={public_key}\n").as_str());
-        buff.push_str("replace_allowed_ips=true\n".to_string().as_str());
-        buff.push_str(format!("endpoint={}\n", conf.peer_address).as_str());
-        buff.push_str("persistent_keepalive_interval=10\n".to_string().as_str());
-        for route in &conf.route {
-            if route.contains("/") {
-                buff.push_str(format!("allowed_ip={route}\n").as_str());
-            } else {
-                buff.push_str(format!("allowed_ip={route}/32\n").as_str());
+        // wg-corplink adds routes without checking, so don't add existing routes again
+        #[cfg(target_os = "linux")]
+        let routes = match reconcile_routes(&self.name, &conf.routes) {
+            Ok(routes) => routes,
+            Err(err) => {
+                log::warn!("failed to check routes of {}: {}", self.name, err);
+                conf.routes.clone()
             }
+        };
+        #[cfg(not(target_os = "linux"))]
+        let routes = conf.routes.clone();
+        log::info!("send config to uapi");
+        let mut applied = false;
+        for (step, buff) in config_steps(conf, &routes) {
+            let data = uapi(buff.as_bytes());
+            let s = String::from_utf8(data).unwrap();
+            if !s.contains("errno=0") {
+                if applied {
+                    // don't leave a half configured interface up
+                    self.down();
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("uapi failed to apply {}: {}", step, s.trim()),
+                ));
+            }
+            applied = true;
         }
+        Ok(())
+    }
 
-        // wg-corplink uapi operations
-        let addr = format!("{}/{}", conf.address, conf.mask);
-        let mtu = conf.mtu;
-        buff.push_str(format!("address={addr}\n").as_str());
-        buff.push_str(format!("mtu={mtu}\n").as_str());
-        buff.push_str("up=true\n".to_string().as_str());
-        for route in &conf.route {
-            if route.contains("/") {
-                buff.push_str(format!("route={route}\n").as_str());
-            } else {
-                buff.push_str(format!("route={route}/32\n").as_str());
+    fn down(&self) {
+        let data = uapi(b"set=1\nup=false\n\n");
+        let s = String::from_utf8(data).unwrap();
+        if s.contains("errno=0") {
+            log::info!("{} is down because of config failure", self.name);
+        } else {
+            log::warn!("failed to bring {} down: {}", self.name, s.trim());
+        }
+    }
+
+    // poll uapi until wg device is ready, returns how long it takes
+    pub async fn wait_ready(&mut self, timeout: time::Duration) -> io::Result<time::Duration> {
+        let start = time::Instant::now();
+        loop {
+            let data = uapi(b"get=1\n\n");
+            let s = String::from_utf8(data).unwrap();
+            if s.contains("errno=0") {
+                return Ok(start.elapsed());
             }
+            if start.elapsed() >= timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "uapi of {} is not ready in {}s, try a larger uapi_ready_timeout_secs: {}",
+                        self.name,
+                        timeout.as_secs(),
+                        s.trim()
+                    ),
+                ));
+            }
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
         }
-        // end operation
+    }
 
-        buff.push('\n');
-        log::info!("send config to uapi");
-        let data = uapi(buff.as_bytes());
+    // retry config until timeout because wg device may be busy right after start
+    pub async fn config_wg_with_retry(
+        &mut self,
+        conf: &config::WgConf,
+        timeout: time::Duration,
+    ) -> io::Result<()> {
+        let start = time::Instant::now();
+        loop {
+            match self.config_wg(conf).await {
+                Ok(_) => return Ok(()),
+                Err(err) => {
+                    if start.elapsed() >= timeout {
+                        return Err(err);
+                    }
+                    log::warn!("failed to config {}: {}, retry", self.name, err);
+                    tokio::time::sleep(time::Duration::from_millis(500)).await;
+                }
+            }
+        }
+    }
+
+    // statistics of the peer, None if uapi returns no peer
+    pub fn get_stats(&self) -> Option<Stats> {
+        let data = uapi(b"get=1\n\n");
         let s = String::from_utf8(data).unwrap();
-        if !s.contains("errno=0") {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                format!("uapi returns unexpected result: {}", s),
-            ));
+        let mut stats = Stats::default();
+        let mut has_peer = false;
+        for line in s.split('\n') {
+            let (key, value) = match line.trim_end().split_once('=') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            match key {
+                "public_key" => has_peer = true,
+                "endpoint" => stats.endpoint = value.to_string(),
+                "last_handshake_time_sec" => {
+                    stats.last_handshake_time = value.parse().unwrap_or_default()
+                }
+                "rx_bytes" => stats.rx_bytes = value.parse().unwrap_or_default(),
+                "tx_bytes" => stats.tx_bytes = value.parse().unwrap_or_default(),
+                _ => {}
+            }
         }
-        Ok(())
+        if !has_peer {
+            return None;
+        }
+        Some(stats)
+    }
+
+    pub fn last_handshake_time(&self) -> Option<i64> {
+        self.get_stats().map(|stats| stats.last_handshake_time)
+    }
+
+    // poll until the first handshake is done, returns false if timeout
+    pub async fn wait_handshake(&mut self, timeout: time::Duration) -> bool {
+        self.wait_handshake_since(0, timeout).await
+    }
+
+    // poll until a handshake later than since is done, returns false if timeout
+    pub async fn wait_handshake_since(&mut self, since: i64, timeout: time::Duration) -> bool {
+        let start = time::Instant::now();
+        while start.elapsed() < timeout {
+            if let Some(timestamp) = self.last_handshake_time() {
+                if timestamp > since {
+                    log::info!("handshake is done in {:.1}s", start.elapsed().as_secs_f32());
+                    return true;
+                }
+            }
+            tokio::time::sleep(time::Duration::from_secs(1)).await;
+        }
+        false
     }
 
     pub async fn check_wg_connection(&mut self) {
-        // default refresh key timeout of wg is 2 min
-        // we set wg connection timeout to 5 min
-        let interval = time::Duration::from_secs(5 * 60);
-        let mut ticker = tokio::time::interval(interval);
+        let interval = WG_CHECK_INTERVAL;
         let mut timeout = false;
-        // consume the first tick
-        ticker.tick().await;
+        let mut last_stats = self.get_stats();
+        let mut last_check = time::Instant::now();
         while !timeout {
-            ticker.tick().await;
+            tokio::time::sleep(utils::jitter(interval, self.jitter_percent)).await;
+
+            // handshake only proves the peer is alive, log throughput to see if traffic flows
+            let stats = self.get_stats();
+            if let (Some(last), Some(now)) = (&last_stats, &stats) {
+                let secs = last_check.elapsed().as_secs_f64();
+                let rx = now.rx_bytes.saturating_sub(last.rx_bytes);
+                let tx = now.tx_bytes.saturating_sub(last.tx_bytes);
+                log::info!(
+                    "{} transferred rx {} bytes ({:.1} B/s), tx {} bytes ({:.1} B/s) with {} in last {:.0}s",
+                    self.name,
+                    rx,
+                    rx as f64 / secs,
+                    tx,
+                    tx as f64 / secs,
+                    now.endpoint,
+                    secs
+                );
+            }
+            last_stats = stats;
+            last_check = time::Instant::now();
 
             let name = self.name.as_str();
             let data = uapi(b"get=1\n\n");